@@ -0,0 +1,494 @@
+pub mod noise;
+pub mod square;
+pub mod wave;
+
+use crate::apu::noise::Noise;
+use crate::apu::square::Square;
+use crate::apu::wave::Wave;
+
+pub const NR10: u16 = 0xFF10;
+pub const NR11: u16 = 0xFF11;
+pub const NR14: u16 = 0xFF14;
+pub const NR21: u16 = 0xFF16;
+pub const NR24: u16 = 0xFF19;
+pub const NR30: u16 = 0xFF1A;
+pub const NR31: u16 = 0xFF1B;
+pub const NR34: u16 = 0xFF1E;
+pub const NR41: u16 = 0xFF20;
+pub const NR44: u16 = 0xFF23;
+pub const NR50: u16 = 0xFF24;
+pub const NR51: u16 = 0xFF25;
+pub const NR52: u16 = 0xFF26;
+pub const WAVE_RAM_START: u16 = 0xFF30;
+pub const WAVE_RAM_END: u16 = 0xFF3F;
+
+// The APU is clocked at 4 MiHz in both CPU speeds
+const T_CYCLES_PER_SECOND: f32 = 4_194_304.0;
+
+// Bits that read back as 1 for 0xFF10-0xFF2F, write-only and unused ones
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+    0x00, 0x00, 0x70, // NR50-NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+// Silences a channel after 64 (256 for the wave channel) steps of 256 Hz
+#[derive(Clone)]
+pub struct LengthCounter {
+    counter: u16,
+    max: u16,
+    enabled: bool,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            counter: 0,
+            max,
+            enabled: false,
+        }
+    }
+
+    fn load(&mut self, value: u16) {
+        self.counter = self.max - value;
+    }
+
+    // True when the counter runs out and the channel should stop
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    // NRx4 writes. Enabling the counter in the half of the frame sequencer
+    // period that doesn't clock it clocks it once straight away, which can
+    // also stop the channel
+    fn write_enable(&mut self, enabled: bool, trigger: bool, extra_clock: bool) -> bool {
+        let mut expired = false;
+        if extra_clock && enabled && !self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            expired = self.counter == 0 && !trigger;
+        }
+        self.enabled = enabled;
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if enabled && extra_clock {
+                self.counter -= 1;
+            }
+        }
+        expired
+    }
+}
+
+// NRx2: starting volume, direction and period of the 64 Hz volume ramp
+#[derive(Clone)]
+pub struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.initial = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x7;
+    }
+
+    // The top five bits of NRx2 double as the channel's DAC power
+    fn dac_enabled(&self) -> bool {
+        self.initial != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer != 0 {
+            return;
+        }
+        self.timer = self.period;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Apu {
+    powered: bool,
+    cgb: bool,
+    // Last values written to 0xFF10-0xFF2F, for reading back
+    registers: [u8; 0x20],
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    // The next of the frame sequencer's eight 512 Hz steps
+    frame_step: u8,
+    // 0 when nobody is listening, so no samples pile up
+    sample_rate: u32,
+    rate_adjustment: f32,
+    cycles_per_sample: f32,
+    // T-cycles and summed output since the last sample, for averaging
+    sample_cycles: f32,
+    left_sum: f32,
+    right_sum: f32,
+    // The high-pass filter on each output that removes the DACs' DC offset
+    left_capacitor: f32,
+    right_capacitor: f32,
+    capacitor_charge: f32,
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new(cgb: bool) -> Apu {
+        Apu {
+            powered: false,
+            cgb,
+            registers: [0; 0x20],
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_step: 0,
+            sample_rate: 0,
+            rate_adjustment: 1.0,
+            cycles_per_sample: 0.0,
+            sample_cycles: 0.0,
+            left_sum: 0.0,
+            right_sum: 0.0,
+            left_capacitor: 0.0,
+            right_capacitor: 0.0,
+            capacitor_charge: 0.0,
+            samples: Vec::new(),
+        }
+    }
+
+    // Samples per second for each of the left and right outputs, 0 for none
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.rate_adjustment = 1.0;
+        if sample_rate > 0 {
+            self.cycles_per_sample = T_CYCLES_PER_SECOND / sample_rate as f32;
+            self.capacitor_charge = 0.999958f32.powf(self.cycles_per_sample);
+        }
+    }
+
+    // Produces slightly more (above 1.0) or fewer samples per emulated second
+    // than the sample rate, so frame pacing can keep an audio buffer level
+    pub fn set_rate_adjustment(&mut self, ratio: f32) {
+        if self.sample_rate > 0 && ratio != self.rate_adjustment {
+            self.rate_adjustment = ratio;
+            self.cycles_per_sample = T_CYCLES_PER_SECOND / (self.sample_rate as f32 * ratio);
+        }
+    }
+
+    // Interleaved left and right samples produced since the last call
+    pub fn take_samples(&mut self) -> Vec<i16> {
+        self.samples.drain(..).collect()
+    }
+
+    // The boot ROM leaves the APU on with its chime played out on channel 1
+    pub fn skip_bootrom(&mut self, io: &[(u16, u8)]) {
+        self.write(NR52, 0x80);
+        for (address, value) in io {
+            match *address {
+                NR14 | NR24 | NR34 | NR44 => self.write(*address, value & 0x7F),
+                NR52 if value & 0x01 != 0 => self.square1.finish_boot_sound(),
+                NR10..=NR51 => self.write(*address, *value),
+                _ => {}
+            }
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            NR52 => {
+                let channels = [
+                    self.square1.enabled,
+                    self.square2.enabled,
+                    self.wave.enabled,
+                    self.noise.enabled,
+                ];
+                let status = channels
+                    .iter()
+                    .enumerate()
+                    .fold(0, |status, (i, on)| status | (*on as u8) << i);
+                (self.powered as u8) << 7 | 0x70 | status
+            }
+            WAVE_RAM_START..=WAVE_RAM_END => {
+                self.wave.read_ram((address - WAVE_RAM_START) as usize)
+            }
+            _ => {
+                let index = (address - NR10) as usize;
+                self.registers[index] | READ_MASKS[index]
+            }
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            NR52 => self.set_power(value & 0x80 != 0),
+            WAVE_RAM_START..=WAVE_RAM_END => self
+                .wave
+                .write_ram((address - WAVE_RAM_START) as usize, value),
+            // Writes are ignored while powered off, except that the DMG
+            // still lets the length counters be loaded
+            NR10..=NR51 if !self.powered => match address {
+                _ if self.cgb => {}
+                NR11 => self.square1.length.load(value as u16 & 0x3F),
+                NR21 => self.square2.length.load(value as u16 & 0x3F),
+                NR31 => self.wave.length.load(value as u16),
+                NR41 => self.noise.length.load(value as u16 & 0x3F),
+                _ => {}
+            },
+            NR10..=NR51 => {
+                self.registers[(address - NR10) as usize] = value;
+                let extra_clock = self.frame_step & 1 == 1;
+                match address {
+                    NR10..=NR14 => self.square1.write(address - NR10, value, extra_clock),
+                    // NR20 doesn't exist, channel 2 has no sweep
+                    0xFF15..=NR24 => self.square2.write(address - 0xFF15, value, extra_clock),
+                    NR30..=NR34 => self.wave.write(address - NR30, value, extra_clock),
+                    0xFF1F..=NR44 => self.noise.write(address - 0xFF1F, value, extra_clock),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn set_power(&mut self, on: bool) {
+        if on && !self.powered {
+            self.powered = true;
+            self.frame_step = 0;
+            self.square1.reset_duty();
+            self.square2.reset_duty();
+            self.wave.reset_sample_buffer();
+        } else if !on && self.powered {
+            self.powered = false;
+            self.registers = [0; 0x20];
+            let lengths = [
+                self.square1.length.counter,
+                self.square2.length.counter,
+                self.wave.length.counter,
+                self.noise.length.counter,
+            ];
+            self.square1 = Square::new(true);
+            self.square2 = Square::new(false);
+            self.wave.power_off();
+            self.noise = Noise::new();
+            if !self.cgb {
+                self.square1.length.counter = lengths[0];
+                self.square2.length.counter = lengths[1];
+                self.wave.length.counter = lengths[2];
+                self.noise.length.counter = lengths[3];
+            }
+        }
+    }
+
+    // Called on each falling edge of DIV bit 4 (bit 5 in double speed)
+    pub fn step_frame_sequencer(&mut self) {
+        if !self.powered {
+            return;
+        }
+        // Length at 256 Hz, sweep at 128 Hz and envelopes at 64 Hz
+        match self.frame_step {
+            0 | 4 => self.clock_lengths(),
+            2 | 6 => {
+                self.clock_lengths();
+                self.square1.clock_sweep();
+            }
+            7 => {
+                self.square1.clock_envelope();
+                self.square2.clock_envelope();
+                self.noise.clock_envelope();
+            }
+            _ => {}
+        }
+        self.frame_step = (self.frame_step + 1) & 0x7;
+    }
+
+    fn clock_lengths(&mut self) {
+        self.square1.clock_length();
+        self.square2.clock_length();
+        self.wave.clock_length();
+        self.noise.clock_length();
+    }
+
+    // The APU runs at the same rate in double speed, so it only gets half
+    // as many T-cycles for each CPU M-cycle
+    pub fn tick(&mut self, cycles: u32, double_speed: bool) {
+        let t_cycles = if double_speed { 2 } else { 4 };
+        for _ in 0..cycles {
+            if self.powered {
+                self.square1.tick(t_cycles);
+                self.square2.tick(t_cycles);
+                self.wave.tick(t_cycles);
+                self.noise.tick(t_cycles);
+            }
+            if self.sample_rate > 0 {
+                self.mix(t_cycles as f32);
+            }
+        }
+    }
+
+    fn mix(&mut self, cycles: f32) {
+        let channels = [
+            (self.square1.dac_enabled(), self.square1.output()),
+            (self.square2.dac_enabled(), self.square2.output()),
+            (self.wave.dac_enabled(), self.wave.output()),
+            (self.noise.dac_enabled(), self.noise.output()),
+        ];
+        let panning = self.registers[(NR51 - NR10) as usize];
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, (dac_enabled, output)) in channels.iter().enumerate() {
+            if !dac_enabled {
+                continue;
+            }
+            let analog = *output as f32 / 7.5 - 1.0;
+            if panning & (0x10 << i) != 0 {
+                left += analog;
+            }
+            if panning & (0x01 << i) != 0 {
+                right += analog;
+            }
+        }
+        let volume = self.registers[(NR50 - NR10) as usize];
+        self.left_sum += left / 4.0 * (((volume >> 4) & 0x7) + 1) as f32 / 8.0 * cycles;
+        self.right_sum += right / 4.0 * ((volume & 0x7) + 1) as f32 / 8.0 * cycles;
+        self.sample_cycles += cycles;
+
+        if self.sample_cycles >= self.cycles_per_sample {
+            let left = self.left_sum / self.sample_cycles;
+            let right = self.right_sum / self.sample_cycles;
+            let left = high_pass(&mut self.left_capacitor, left, self.capacitor_charge);
+            let right = high_pass(&mut self.right_capacitor, right, self.capacitor_charge);
+            self.samples.push(to_sample(left));
+            self.samples.push(to_sample(right));
+            self.sample_cycles -= self.cycles_per_sample;
+            self.left_sum = 0.0;
+            self.right_sum = 0.0;
+        }
+    }
+}
+
+fn high_pass(capacitor: &mut f32, input: f32, charge: f32) -> f32 {
+    let output = input - *capacitor;
+    *capacitor = input - output * charge;
+    output
+}
+
+fn to_sample(value: f32) -> i16 {
+    (value * i16::MAX as f32)
+        .max(i16::MIN as f32)
+        .min(i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn powered_apu() -> Apu {
+        let mut apu = Apu::new(false);
+        apu.write(NR52, 0x80);
+        apu.write(NR50, 0x77);
+        apu.write(NR51, 0xFF);
+        apu
+    }
+
+    #[test]
+    fn registers_read_back_with_unused_bits_set() {
+        let mut apu = powered_apu();
+        apu.write(NR11, 0x80);
+        apu.write(0xFF13, 0x12);
+        assert_eq!(apu.read(NR11), 0xBF);
+        assert_eq!(apu.read(0xFF13), 0xFF);
+        assert_eq!(apu.read(0xFF27), 0xFF);
+        assert_eq!(apu.read(NR52), 0xF0);
+    }
+
+    #[test]
+    fn power_off_clears_registers_but_not_wave_ram() {
+        let mut apu = powered_apu();
+        apu.write(WAVE_RAM_START, 0x5A);
+        apu.write(0xFF12, 0xF0);
+        apu.write(NR14, 0x80);
+        assert_eq!(apu.read(NR52), 0xF1);
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR52), 0x70);
+        assert_eq!(apu.read(NR50), 0x00);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        // Writes are ignored until the APU is powered again
+        apu.write(0xFF12, 0xF0);
+        assert_eq!(apu.read(0xFF12), 0x00);
+        assert_eq!(apu.read(WAVE_RAM_START), 0x5A);
+    }
+
+    #[test]
+    fn length_counter_stops_the_channel() {
+        let mut apu = powered_apu();
+        apu.write(0xFF17, 0xF0);
+        apu.write(NR21, 0x3C);
+        apu.write(NR24, 0xC0);
+        // A length of 4 lasts four of the steps that clock length
+        for _ in 0..6 {
+            apu.step_frame_sequencer();
+        }
+        assert_eq!(apu.read(NR52) & 0x02, 0x02);
+        apu.step_frame_sequencer();
+        assert_eq!(apu.read(NR52) & 0x02, 0x00);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_channel_one() {
+        let mut apu = powered_apu();
+        apu.write(0xFF12, 0xF0);
+        apu.write(NR10, 0x11);
+        apu.write(0xFF13, 0xFF);
+        apu.write(NR14, 0x87);
+        // 2047 + (2047 >> 1) overflows as soon as the channel is triggered
+        assert_eq!(apu.read(NR52) & 0x01, 0x00);
+    }
+
+    #[test]
+    fn produces_stereo_samples_at_the_requested_rate() {
+        let mut apu = powered_apu();
+        apu.set_sample_rate(32768);
+        apu.write(0xFF12, 0xF0);
+        apu.write(NR11, 0x80);
+        apu.write(NR14, 0x84);
+        // 128 Hz for one second of M-cycles
+        apu.tick(1_048_576, false);
+        let samples = apu.take_samples();
+        assert_eq!(samples.len(), 2 * 32768);
+        assert!(samples.iter().any(|sample| *sample > 1000));
+        assert!(samples.iter().any(|sample| *sample < -1000));
+    }
+}
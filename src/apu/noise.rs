@@ -0,0 +1,109 @@
+use crate::apu::{Envelope, LengthCounter};
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+// Channel 4 outputs the inverted low bit of a linear feedback shift register
+#[derive(Clone)]
+pub struct Noise {
+    pub enabled: bool,
+    pub length: LengthCounter,
+    envelope: Envelope,
+    shift: u8,
+    // 7 bit mode copies feedback into bit 6 too, for a shorter, buzzier cycle
+    short_mode: bool,
+    divisor_code: u8,
+    lfsr: u16,
+    timer: u32,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            enabled: false,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            shift: 0,
+            short_mode: false,
+            divisor_code: 0,
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn write(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            1 => self.length.load(value as u16 & 0x3F),
+            2 => {
+                self.envelope.write(value);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => {
+                self.shift = value >> 4;
+                self.short_mode = value & 0x08 != 0;
+                self.divisor_code = value & 0x7;
+            }
+            4 => {
+                let trigger = value & 0x80 != 0;
+                if self
+                    .length
+                    .write_enable(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.dac_enabled();
+                    self.lfsr = 0x7FFF;
+                    self.timer = self.period();
+                    self.envelope.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor_code as usize] << self.shift
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        // Shifts of 14 and 15 stop the LFSR being clocked at all
+        if !self.enabled || self.shift >= 14 {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = self.period();
+            let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+            if self.short_mode {
+                self.lfsr = (self.lfsr & !0x40) | (feedback << 6);
+            }
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
@@ -0,0 +1,203 @@
+use crate::apu::{Envelope, LengthCounter};
+
+// Fraction of each period the output is high: 12.5%, 25%, 50% and 75%
+const DUTY_PATTERNS: [u8; 4] = [0b0000_0001, 0b1000_0001, 0b1000_0111, 0b0111_1110];
+
+// Channel 1's frequency sweep, NR10
+#[derive(Clone)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+    // Set once a subtraction has been calculated since the last trigger
+    negated: bool,
+}
+
+impl Sweep {
+    fn new() -> Sweep {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            timer: 0,
+            enabled: false,
+            shadow: 0,
+            negated: false,
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    // Frequencies above 2047 overflow and switch the channel off
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+// Channels 1 and 2. Only channel 1 has the sweep unit
+#[derive(Clone)]
+pub struct Square {
+    pub enabled: bool,
+    sweep: Option<Sweep>,
+    duty: u8,
+    duty_position: u8,
+    frequency: u16,
+    // T-cycles until the next duty step
+    timer: u32,
+    pub length: LengthCounter,
+    envelope: Envelope,
+}
+
+impl Square {
+    pub fn new(sweep: bool) -> Square {
+        Square {
+            enabled: false,
+            sweep: if sweep { Some(Sweep::new()) } else { None },
+            duty: 0,
+            duty_position: 0,
+            frequency: 0,
+            timer: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    // Registers NRx0-NRx4 as 0-4. extra_length_clock is set when the frame
+    // sequencer's next step won't clock the length counter
+    pub fn write(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    sweep.period = (value >> 4) & 0x7;
+                    sweep.negate = value & 0x08 != 0;
+                    sweep.shift = value & 0x7;
+                    // Leaving negate mode after using it disables the channel
+                    if !sweep.negate && sweep.negated {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value as u16 & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x7) << 8);
+                let trigger = value & 0x80 != 0;
+                if self
+                    .length
+                    .write_enable(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.timer = (2048 - self.frequency as u32) * 4;
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            sweep.shadow = self.frequency;
+            sweep.negated = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            if sweep.shift != 0 && sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency as u32) * 4;
+            self.duty_position = (self.duty_position + 1) & 0x7;
+        }
+        self.timer -= cycles;
+    }
+
+    // Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if self.enabled && DUTY_PATTERNS[self.duty as usize] & (0x80 >> self.duty_position) != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let sweep = match &mut self.sweep {
+            Some(sweep) => sweep,
+            None => return,
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let frequency = sweep.calculate();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked again straight away
+            if sweep.calculate() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    // Powering the APU on restarts the duty cycle
+    pub fn reset_duty(&mut self) {
+        self.duty_position = 0;
+    }
+
+    // The boot ROM's chime leaves channel 1 on, but silent
+    pub fn finish_boot_sound(&mut self) {
+        self.enabled = self.dac_enabled();
+        self.envelope.volume = 0;
+    }
+}
@@ -0,0 +1,131 @@
+use crate::apu::LengthCounter;
+
+// Channel 3 plays 32 4-bit samples from wave RAM, high nibble first
+#[derive(Clone)]
+pub struct Wave {
+    pub enabled: bool,
+    dac: bool,
+    pub length: LengthCounter,
+    // NR32 bits 5-6: mute, 100%, 50% or 25%
+    volume_code: u8,
+    frequency: u16,
+    timer: u32,
+    position: u8,
+    sample_buffer: u8,
+    ram: [u8; 16],
+}
+
+impl Wave {
+    pub fn new() -> Wave {
+        Wave {
+            enabled: false,
+            dac: false,
+            length: LengthCounter::new(256),
+            volume_code: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample_buffer: 0,
+            ram: [0; 16],
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac
+    }
+
+    pub fn write(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                self.dac = value & 0x80 != 0;
+                if !self.dac {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value as u16),
+            2 => self.volume_code = (value >> 5) & 0x3,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x7) << 8);
+                let trigger = value & 0x80 != 0;
+                if self
+                    .length
+                    .write_enable(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.enabled = self.dac;
+                    self.timer = (2048 - self.frequency as u32) * 2;
+                    self.position = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // While the channel plays, the CPU sees the byte it is currently reading
+    pub fn read_ram(&self, offset: usize) -> u8 {
+        if self.enabled {
+            self.ram[self.position as usize / 2]
+        } else {
+            self.ram[offset]
+        }
+    }
+
+    pub fn write_ram(&mut self, offset: usize, value: u8) {
+        if self.enabled {
+            self.ram[self.position as usize / 2] = value;
+        } else {
+            self.ram[offset] = value;
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles >= self.timer {
+            cycles -= self.timer;
+            self.timer = (2048 - self.frequency as u32) * 2;
+            self.position = (self.position + 1) & 0x1F;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample_buffer = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0xF
+            };
+        }
+        self.timer -= cycles;
+    }
+
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        match self.volume_code {
+            0 => 0,
+            code => self.sample_buffer >> (code - 1),
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    // The buffer is cleared on power on, wave RAM keeps its contents
+    pub fn reset_sample_buffer(&mut self) {
+        self.sample_buffer = 0;
+    }
+
+    // Powering off clears everything but wave RAM
+    pub fn power_off(&mut self) {
+        *self = Wave {
+            ram: self.ram,
+            ..Wave::new()
+        };
+    }
+}
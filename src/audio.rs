@@ -0,0 +1,29 @@
+pub mod player;
+pub mod ring;
+pub mod wav;
+
+use std::io;
+
+// Where the APU's interleaved 16 bit stereo samples end up
+pub trait AudioSink {
+    // Must not block emulation for long, real-time sinks drop what won't fit
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+    // Samples queued for a real-time device but not played yet, for pacing
+    // emulation to the audio clock. None for sinks that aren't real-time
+    fn buffered(&self) -> Option<usize> {
+        None
+    }
+    // Flushes anything buffered, for sinks that write files
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Throws samples away, for running without sound
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn write(&mut self, _samples: &[i16]) -> io::Result<()> {
+        Ok(())
+    }
+}
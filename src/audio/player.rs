@@ -0,0 +1,210 @@
+use std::io::{self, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::audio::ring::RingBuffer;
+use crate::audio::AudioSink;
+
+// How much audio can queue up before the oldest is dropped
+const BUFFER_MILLIS: u32 = 100;
+// Samples handed to the player at a time
+const CHUNK_SAMPLES: usize = 1024;
+// How long the writer waits for a full chunk before sending what it has
+const CHUNK_WAIT: Duration = Duration::from_millis(10);
+
+struct Queue {
+    ring: RingBuffer,
+    closed: bool,
+    // Samples handed to the player that it hasn't played yet. The pipe and
+    // the player's own buffer hide this, so it's worked out from how long the
+    // player has had them at the sample rate
+    in_player: f64,
+    updated: Instant,
+    samples_per_second: f64,
+}
+
+impl Queue {
+    fn update_in_player(&mut self) {
+        let now = Instant::now();
+        let played = (now - self.updated).as_secs_f64() * self.samples_per_second;
+        // Running dry means the player is idle, not that it's owed samples
+        self.in_player = (self.in_player - played).max(0.0);
+        self.updated = now;
+    }
+}
+
+// Real-time playback by piping raw samples into a command line player. A
+// writer thread feeds the pipe from a ring buffer, so a slow or stalled player
+// costs dropped audio rather than emulation speed
+pub struct PlayerSink {
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    child: Child,
+    writer: Option<JoinHandle<()>>,
+    pub player: &'static str,
+}
+
+impl PlayerSink {
+    // Tries aplay (ALSA), pacat (PulseAudio) and play (SoX) in that order
+    pub fn spawn(sample_rate: u32) -> io::Result<PlayerSink> {
+        let rate = sample_rate.to_string();
+        let buffer_micros = (BUFFER_MILLIS * 1000).to_string();
+        let players: [(&'static str, Vec<&str>); 3] = [
+            (
+                "aplay",
+                vec![
+                    "-q",
+                    "-t",
+                    "raw",
+                    "-f",
+                    "S16_LE",
+                    "-c",
+                    "2",
+                    "-r",
+                    &rate,
+                    "--buffer-time",
+                    &buffer_micros,
+                    "-",
+                ],
+            ),
+            (
+                "pacat",
+                vec!["--raw", "--format=s16le", "--channels=2", "--rate", &rate],
+            ),
+            (
+                "play",
+                vec![
+                    "-q", "-t", "raw", "-e", "signed", "-b", "16", "-c", "2", "-r", &rate, "-",
+                ],
+            ),
+        ];
+        for (player, args) in players.iter() {
+            let child = Command::new(player)
+                .args(args)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+            if let Ok(mut child) = child {
+                let stdin = child.stdin.take().unwrap();
+                return Ok(PlayerSink::start(sample_rate, child, stdin, player));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no audio player found, tried aplay, pacat and play",
+        ))
+    }
+
+    fn start(
+        sample_rate: u32,
+        child: Child,
+        stdin: ChildStdin,
+        player: &'static str,
+    ) -> PlayerSink {
+        let capacity = (sample_rate * BUFFER_MILLIS / 1000) as usize * 2;
+        let queue = Arc::new((
+            Mutex::new(Queue {
+                ring: RingBuffer::new(capacity),
+                closed: false,
+                in_player: 0.0,
+                updated: Instant::now(),
+                samples_per_second: sample_rate as f64 * 2.0,
+            }),
+            Condvar::new(),
+        ));
+        let writer_queue = Arc::clone(&queue);
+        let writer = thread::spawn(move || write_to_player(writer_queue, stdin));
+        PlayerSink {
+            queue,
+            child,
+            writer: Some(writer),
+            player,
+        }
+    }
+}
+
+fn write_to_player(queue: Arc<(Mutex<Queue>, Condvar)>, mut stdin: ChildStdin) {
+    let (lock, ready) = &*queue;
+    let mut chunk = [0; CHUNK_SAMPLES];
+    let mut bytes = Vec::with_capacity(CHUNK_SAMPLES * 2);
+    loop {
+        let count = {
+            let mut queue = lock.lock().unwrap();
+            if queue.ring.len() < CHUNK_SAMPLES && !queue.closed {
+                queue = ready.wait_timeout(queue, CHUNK_WAIT).unwrap().0;
+            }
+            if queue.closed {
+                return;
+            }
+            let count = queue.ring.pop(&mut chunk);
+            queue.update_in_player();
+            queue.in_player += count as f64;
+            count
+        };
+        bytes.clear();
+        for sample in &chunk[..count] {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        if stdin.write_all(&bytes).is_err() {
+            // Closing the sink kills the player, which fails the write
+            if !lock.lock().unwrap().closed {
+                eprintln!("Audio player stopped, continuing without sound");
+            }
+            return;
+        }
+    }
+}
+
+impl AudioSink for PlayerSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let (lock, ready) = &*self.queue;
+        lock.lock().unwrap().ring.push(samples);
+        ready.notify_one();
+        Ok(())
+    }
+
+    // Everything the player will get through before a sample written now
+    fn buffered(&self) -> Option<usize> {
+        let mut queue = self.queue.0.lock().unwrap();
+        queue.update_in_player();
+        Some(queue.ring.len() + queue.in_player as usize)
+    }
+}
+
+impl Drop for PlayerSink {
+    fn drop(&mut self) {
+        let (lock, ready) = &*self.queue;
+        lock.lock().unwrap().closed = true;
+        ready.notify_one();
+        // Killing the player makes a blocked write fail so the thread can exit
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn player_share_drains_at_the_sample_rate() {
+        let mut queue = Queue {
+            ring: RingBuffer::new(16),
+            closed: false,
+            in_player: 9600.0,
+            updated: Instant::now() - Duration::from_millis(50),
+            samples_per_second: 96000.0,
+        };
+        queue.update_in_player();
+        // 50 ms of 48 kHz stereo is 4800 samples
+        assert!((queue.in_player - 4800.0).abs() < 500.0);
+        queue.updated -= Duration::from_secs(1);
+        queue.update_in_player();
+        assert_eq!(queue.in_player, 0.0);
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+
+// Fixed size sample queue between emulation and an audio device. Neither side
+// waits on the other: when it overflows the oldest samples are dropped to keep
+// latency down, and when it runs dry the reader gets what there is
+pub struct RingBuffer {
+    samples: VecDeque<i16>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> RingBuffer {
+        RingBuffer {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn push(&mut self, samples: &[i16]) {
+        let samples = &samples[samples.len().saturating_sub(self.capacity)..];
+        let overflow = (self.samples.len() + samples.len()).saturating_sub(self.capacity);
+        if overflow > 0 {
+            // Drop whole stereo frames so left and right don't swap
+            let dropped = (overflow + overflow % 2).min(self.samples.len());
+            self.samples.drain(..dropped);
+        }
+        self.samples.extend(samples);
+    }
+
+    // Fills as much of out as it can and returns how many samples that was
+    pub fn pop(&mut self, out: &mut [i16]) -> usize {
+        let count = out.len().min(self.samples.len());
+        for (slot, sample) in out.iter_mut().zip(self.samples.drain(..count)) {
+            *slot = sample;
+        }
+        count
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow_drops_the_oldest_frames() {
+        let mut ring = RingBuffer::new(4);
+        ring.push(&[1, 2, 3, 4]);
+        ring.push(&[5, 6]);
+        let mut out = [0; 4];
+        assert_eq!(ring.pop(&mut out), 4);
+        assert_eq!(out, [3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn pop_returns_what_is_left() {
+        let mut ring = RingBuffer::new(8);
+        ring.push(&[1, 2]);
+        let mut out = [0; 4];
+        assert_eq!(ring.pop(&mut out), 2);
+        assert_eq!(&out[..2], &[1, 2]);
+        assert_eq!(ring.len(), 0);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::audio::AudioSink;
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+// 16 bit stereo PCM WAV. The sizes in the header are filled in by finish
+pub struct WavSink {
+    file: BufWriter<File>,
+    data_bytes: u32,
+    finished: bool,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, sample_rate: u32) -> io::Result<WavSink> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&header(sample_rate, 0))?;
+        Ok(WavSink {
+            file,
+            data_bytes: 0,
+            finished: false,
+        })
+    }
+}
+
+fn header(sample_rate: u32, data_bytes: u32) -> Vec<u8> {
+    let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+    let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(HEADER_SIZE - 8 + data_bytes).to_le_bytes());
+    header.extend_from_slice(b"WAVEfmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // Format 1 is uncompressed PCM
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&CHANNELS.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_bytes.to_le_bytes());
+    header
+}
+
+impl AudioSink for WavSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_bytes += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        // Only the two size fields change, so patch them in place
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + self.data_bytes).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.file.write_all(&self.data_bytes.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+// A WAV that was never finished would claim to hold no audio
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Could not finish WAV file: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn header_sizes_match_the_samples_written() {
+        let path = env::temp_dir().join(format!("gbemu-wav-{}.wav", std::process::id()));
+        let mut sink = WavSink::create(&path, 48000).unwrap();
+        sink.write(&[0, 0, 100, -100]).unwrap();
+        sink.write(&[i16::MAX, i16::MIN]).unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(bytes[4..8], 48u32.to_le_bytes());
+        assert_eq!(bytes[24..28], 48000u32.to_le_bytes());
+        // 4 bytes per stereo frame
+        assert_eq!(bytes[28..32], 192_000u32.to_le_bytes());
+        assert_eq!(bytes[40..44], 12u32.to_le_bytes());
+        assert_eq!(&bytes[52..56], &[0xFF, 0x7F, 0x00, 0x80]);
+    }
+}
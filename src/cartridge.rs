@@ -0,0 +1,392 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub mod battery;
+pub mod header;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+pub mod mbc5;
+pub mod rtc;
+use crate::cartridge::header::*;
+use crate::cartridge::mbc1::Mbc1;
+use crate::cartridge::mbc2::{Mbc2, MBC2_RAM_SIZE};
+use crate::cartridge::mbc3::Mbc3;
+use crate::cartridge::mbc5::{Mbc5, RumbleEvent};
+use crate::cartridge::rtc::{RtcClock, RtcRegister};
+
+#[derive(Clone)]
+pub struct Cartridge {
+    pub path: Option<PathBuf>,
+    pub header: CartridgeHeader,
+    pub rom: Vec<u8>,
+    pub ram: CartridgeRam,
+    pub mbc: Mbc,
+    pub warnings: Vec<HeaderWarning>,
+}
+
+#[derive(Clone)]
+pub struct CartridgeRam {
+    pub data: Vec<u8>,
+    // MBC2 RAM is 4 bits wide, the upper nibble isn't connected
+    pub half_bytes: bool,
+    // Set on every write so battery saves are only flushed when needed
+    pub dirty: bool,
+}
+
+#[derive(Clone)]
+pub enum Mbc {
+    RomOnly,
+    Mbc1(Mbc1),
+    Mbc2(Mbc2),
+    Mbc3(Mbc3),
+    Mbc5(Mbc5),
+}
+
+pub enum RamTarget {
+    Ram(usize),
+    Rtc(RtcRegister),
+    Unmapped,
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    Header(HeaderError),
+    Truncated { expected: usize, actual: usize },
+    UnsupportedMbc(MbcKind),
+}
+
+impl Cartridge {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Cartridge, CartridgeError> {
+        let rom = fs::read(&path).map_err(CartridgeError::Io)?;
+        let mut cartridge = Cartridge::from_bytes(rom)?;
+        cartridge.path = Some(path.as_ref().to_path_buf());
+        Ok(cartridge)
+    }
+
+    pub fn from_bytes(mut rom: Vec<u8>) -> Result<Cartridge, CartridgeError> {
+        let header = CartridgeHeader::parse(&rom).map_err(CartridgeError::Header)?;
+        if rom.len() < header.rom_size() {
+            return Err(CartridgeError::Truncated {
+                expected: header.rom_size(),
+                actual: rom.len(),
+            });
+        }
+        let warnings = header.validate(&rom);
+        // Anything past the declared size can't be banked in, so drop it
+        rom.truncate(header.rom_size());
+        let mbc = match header.cartridge_type.mbc {
+            MbcKind::RomOnly => Mbc::RomOnly,
+            MbcKind::Mbc1 => Mbc::Mbc1(Mbc1::new()),
+            MbcKind::Mbc2 => Mbc::Mbc2(Mbc2::new()),
+            MbcKind::Mbc3 => Mbc::Mbc3(Mbc3::new(header.cartridge_type.timer)),
+            MbcKind::Mbc5 => Mbc::Mbc5(Mbc5::new(header.cartridge_type.rumble)),
+            kind => return Err(CartridgeError::UnsupportedMbc(kind)),
+        };
+        let ram = match (&mbc, header.cartridge_type.ram) {
+            (Mbc::Mbc2(_), _) => CartridgeRam::new(MBC2_RAM_SIZE, true),
+            (_, true) => CartridgeRam::new(header.ram_size, false),
+            (_, false) => CartridgeRam::new(0, false),
+        };
+        Ok(Cartridge {
+            path: None,
+            header,
+            rom,
+            ram,
+            mbc,
+            warnings,
+        })
+    }
+
+    pub fn read_rom(&self, address: u16) -> u8 {
+        let offset = match &self.mbc {
+            Mbc::RomOnly => address as usize,
+            Mbc::Mbc1(mbc) => mbc.rom_offset(address, self.header.rom_banks),
+            Mbc::Mbc2(mbc) => mbc.rom_offset(address, self.header.rom_banks),
+            Mbc::Mbc3(mbc) => mbc.rom_offset(address, self.header.rom_banks),
+            Mbc::Mbc5(mbc) => mbc.rom_offset(address, self.header.rom_banks),
+        };
+        self.rom[offset]
+    }
+
+    pub fn write_rom(&mut self, address: u16, value: u8) {
+        match &mut self.mbc {
+            Mbc::RomOnly => {}
+            Mbc::Mbc1(mbc) => mbc.write_register(address, value),
+            Mbc::Mbc2(mbc) => mbc.write_register(address, value),
+            Mbc::Mbc3(mbc) => mbc.write_register(address, value),
+            Mbc::Mbc5(mbc) => mbc.write_register(address, value),
+        }
+    }
+
+    pub fn read_ram(&self, address: u16) -> u8 {
+        match (self.ram_target(address), &self.mbc) {
+            (RamTarget::Ram(offset), _) => self.ram.read(offset),
+            (RamTarget::Rtc(register), Mbc::Mbc3(mbc)) => mbc.rtc.as_ref().unwrap().read(register),
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write_ram(&mut self, address: u16, value: u8) {
+        match (self.ram_target(address), &mut self.mbc) {
+            (RamTarget::Ram(offset), _) => self.ram.write(offset, value),
+            (RamTarget::Rtc(register), Mbc::Mbc3(mbc)) => {
+                mbc.rtc.as_mut().unwrap().write(register, value);
+                // The clock is saved with the RAM, so setting it needs a flush too
+                self.ram.dirty = true;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if let Mbc::Mbc3(mbc) = &mut self.mbc {
+            mbc.tick(cycles);
+        }
+    }
+
+    pub fn set_rtc_clock(&mut self, clock: RtcClock) {
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mbc {
+            rtc.set_clock(clock);
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.header.cartridge_type.battery
+    }
+
+    // RAM contents followed by the RTC footer if there is a clock, matching
+    // the raw .sav layout other emulators use
+    pub fn battery_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.data.clone();
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mbc {
+            data.extend(rtc.save());
+        }
+        data
+    }
+
+    pub fn load_battery_data(&mut self, data: &[u8]) {
+        let ram_size = self.ram.data.len().min(data.len());
+        self.ram.data[..ram_size].copy_from_slice(&data[..ram_size]);
+        if let Mbc::Mbc3(Mbc3 { rtc: Some(rtc), .. }) = &mut self.mbc {
+            if data.len() > ram_size {
+                rtc.load(&data[ram_size..]);
+            }
+        }
+    }
+
+    pub fn take_rumble_events(&mut self) -> Vec<RumbleEvent> {
+        match &mut self.mbc {
+            Mbc::Mbc5(mbc) => mbc.rumble_events.drain(..).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn ram_target(&self, address: u16) -> RamTarget {
+        let target = match &self.mbc {
+            Mbc::RomOnly => RamTarget::Ram(address as usize - 0xA000),
+            Mbc::Mbc1(mbc) => match mbc.ram_offset(address) {
+                Some(offset) => RamTarget::Ram(offset),
+                None => RamTarget::Unmapped,
+            },
+            Mbc::Mbc2(mbc) => match mbc.ram_offset(address) {
+                Some(offset) => RamTarget::Ram(offset),
+                None => RamTarget::Unmapped,
+            },
+            Mbc::Mbc3(mbc) => mbc.ram_target(address),
+            Mbc::Mbc5(mbc) => match mbc.ram_offset(address) {
+                Some(offset) => RamTarget::Ram(offset),
+                None => RamTarget::Unmapped,
+            },
+        };
+        match target {
+            RamTarget::Ram(_) if self.ram.data.is_empty() => RamTarget::Unmapped,
+            target => target,
+        }
+    }
+}
+
+impl CartridgeRam {
+    pub fn new(size: usize, half_bytes: bool) -> CartridgeRam {
+        CartridgeRam {
+            data: vec![0; size],
+            half_bytes,
+            dirty: false,
+        }
+    }
+
+    // Carts with less RAM than the selected bank offset mirror it
+    pub fn read(&self, offset: usize) -> u8 {
+        let value = self.data[offset % self.data.len()];
+        if self.half_bytes {
+            value | 0xF0
+        } else {
+            value
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, value: u8) {
+        let len = self.data.len();
+        self.data[offset % len] = if self.half_bytes { value & 0x0F } else { value };
+        self.dirty = true;
+    }
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(e) => write!(f, "could not read ROM: {}", e),
+            CartridgeError::Header(e) => write!(f, "malformed cartridge header: {}", e),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "header declares {} bytes of ROM but image is only {} bytes",
+                expected, actual
+            ),
+            CartridgeError::UnsupportedMbc(kind) => {
+                write!(f, "{} cartridges are not supported", kind)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub fn build_rom(cartridge_type: u8, rom_size: u8, ram_size: u8) -> Vec<u8> {
+        let mut rom = vec![0; (2 << rom_size) * ROM_BANK_SIZE];
+        rom[LOGO_START..=LOGO_END].copy_from_slice(&NINTENDO_LOGO);
+        rom[TITLE_START..TITLE_START + 4].copy_from_slice(b"TEST");
+        rom[CARTRIDGE_TYPE] = cartridge_type;
+        rom[ROM_SIZE] = rom_size;
+        rom[RAM_SIZE] = ram_size;
+        rom[HEADER_CHECKSUM] = CartridgeHeader::compute_header_checksum(&rom);
+        let global_checksum = CartridgeHeader::compute_global_checksum(&rom);
+        rom[GLOBAL_CHECKSUM] = (global_checksum >> 8) as u8;
+        rom[GLOBAL_CHECKSUM + 1] = global_checksum as u8;
+        rom
+    }
+
+    #[test]
+    fn parse_header() {
+        let cartridge = Cartridge::from_bytes(build_rom(0x13, 0x05, 0x03)).unwrap();
+        assert_eq!(cartridge.header.title, "TEST");
+        assert_eq!(cartridge.header.cartridge_type.mbc, MbcKind::Mbc3);
+        assert!(cartridge.header.cartridge_type.battery);
+        assert_eq!(cartridge.header.rom_banks, 64);
+        assert_eq!(cartridge.header.ram_size, 0x8000);
+        assert!(cartridge.warnings.is_empty());
+    }
+
+    #[test]
+    fn warn_on_bad_checksums() {
+        let mut rom = build_rom(0x00, 0x00, 0x00);
+        rom[LOGO_START] = 0;
+        rom[VERSION] = 1;
+        let cartridge = Cartridge::from_bytes(rom).unwrap();
+        assert!(cartridge.warnings.contains(&HeaderWarning::LogoMismatch));
+        assert!(cartridge
+            .warnings
+            .iter()
+            .any(|w| matches!(w, HeaderWarning::HeaderChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn refuse_malformed_images() {
+        assert!(matches!(
+            Cartridge::from_bytes(vec![0; 0x100]),
+            Err(CartridgeError::Header(HeaderError::TooSmall(0x100)))
+        ));
+        let mut rom = build_rom(0x00, 0x00, 0x00);
+        rom[CARTRIDGE_TYPE] = 0x42;
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::Header(HeaderError::UnknownCartridgeType(
+                0x42
+            )))
+        ));
+        let mut rom = build_rom(0x01, 0x02, 0x00);
+        rom.truncate(0x8000);
+        assert!(matches!(
+            Cartridge::from_bytes(rom),
+            Err(CartridgeError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn mbc1_switches_rom_and_ram_banks() {
+        let mut rom = build_rom(0x03, 0x06, 0x03);
+        rom[5 * ROM_BANK_SIZE] = 0x55;
+        rom[0x25 * ROM_BANK_SIZE] = 0x25;
+        let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+        cartridge.write_rom(0x2000, 0x05);
+        assert_eq!(cartridge.read_rom(0x4000), 0x55);
+        cartridge.write_rom(0x4000, 0x01);
+        assert_eq!(cartridge.read_rom(0x4000), 0x25);
+
+        cartridge.write_ram(0xA000, 0x12);
+        assert_eq!(cartridge.read_ram(0xA000), 0xFF);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA000, 0x12);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_ram(0xA000), 0x00);
+        cartridge.write_rom(0x4000, 0x00);
+        assert_eq!(cartridge.read_ram(0xA000), 0x12);
+    }
+
+    #[test]
+    fn mbc2_ram_is_half_bytes_and_mirrored() {
+        let mut cartridge = Cartridge::from_bytes(build_rom(0x06, 0x03, 0x00)).unwrap();
+        assert_eq!(cartridge.ram.data.len(), 512);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA001, 0x5C);
+        assert_eq!(cartridge.read_ram(0xA001), 0xFC);
+        assert_eq!(cartridge.read_ram(0xA201), 0xFC);
+        assert_eq!(cartridge.read_ram(0xBE01), 0xFC);
+        assert_eq!(cartridge.ram.data[1], 0x0C);
+    }
+
+    #[test]
+    fn mbc3_maps_rtc_registers_into_ram_window() {
+        let mut cartridge = Cartridge::from_bytes(build_rom(0x10, 0x06, 0x03)).unwrap();
+        cartridge.set_rtc_clock(RtcClock::Cycles);
+        cartridge.write_rom(0x2000, 0x45);
+        assert_eq!(cartridge.read_rom(0x4000), 0);
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_rom(0x4000, 0x03);
+        cartridge.write_ram(0xA123, 0x77);
+        cartridge.ram.dirty = false;
+        cartridge.write_rom(0x4000, 0x08);
+        cartridge.write_ram(0xA000, 30);
+        assert!(cartridge.ram.dirty);
+        cartridge.tick(1_048_576 * 45);
+        cartridge.write_rom(0x6000, 0x00);
+        cartridge.write_rom(0x6000, 0x01);
+        assert_eq!(cartridge.read_ram(0xA000), 15);
+        cartridge.write_rom(0x4000, 0x09);
+        assert_eq!(cartridge.read_ram(0xA000), 1);
+        cartridge.write_rom(0x4000, 0x03);
+        assert_eq!(cartridge.read_ram(0xA123), 0x77);
+        assert_eq!(cartridge.ram.data[3 * RAM_BANK_SIZE + 0x123], 0x77);
+    }
+
+    #[test]
+    fn mbc5_addresses_8_mib_and_reports_rumble() {
+        let mut rom = build_rom(0x1E, 0x08, 0x04);
+        rom[0x1FF * ROM_BANK_SIZE + 0x10] = 0xAB;
+        let mut cartridge = Cartridge::from_bytes(rom).unwrap();
+        cartridge.write_rom(0x2000, 0xFF);
+        cartridge.write_rom(0x3000, 0x01);
+        assert_eq!(cartridge.read_rom(0x4010), 0xAB);
+        cartridge.write_rom(0x4000, 0x08);
+        cartridge.write_rom(0x4000, 0x00);
+        assert_eq!(
+            cartridge.take_rumble_events(),
+            vec![RumbleEvent::Start, RumbleEvent::Stop]
+        );
+        assert!(cartridge.take_rumble_events().is_empty());
+    }
+}
@@ -0,0 +1,85 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::Cartridge;
+
+pub struct BatterySave {
+    pub path: PathBuf,
+}
+
+impl BatterySave {
+    // <save_dir>/<rom name>.sav, or next to the ROM when no directory is given
+    pub fn for_rom(rom_path: &Path, save_dir: Option<&Path>) -> BatterySave {
+        let path = rom_path.with_extension("sav");
+        let path = match (save_dir, path.file_name()) {
+            (Some(dir), Some(file_name)) => dir.join(file_name),
+            _ => path,
+        };
+        BatterySave { path }
+    }
+
+    // Returns false when there was no save to load yet
+    pub fn load(&self, cartridge: &mut Cartridge) -> io::Result<bool> {
+        match fs::read(&self.path) {
+            Ok(data) => {
+                cartridge.load_battery_data(&data);
+                Ok(true)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Write to a temporary file and rename it over the old save so a crash
+    // mid-write can never leave a truncated .sav behind
+    pub fn flush(&self, cartridge: &mut Cartridge) -> io::Result<()> {
+        let data = cartridge.battery_data();
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        if let Some(dir) = self.path.parent() {
+            if !dir.as_os_str().is_empty() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        fs::write(&tmp_path, &data)?;
+        fs::rename(&tmp_path, &self.path)?;
+        cartridge.ram.dirty = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test::build_rom;
+    use std::env;
+
+    #[test]
+    fn save_path_uses_rom_name() {
+        let save = BatterySave::for_rom(Path::new("roms/game.gb"), None);
+        assert_eq!(save.path, Path::new("roms/game.sav"));
+        let save = BatterySave::for_rom(Path::new("roms/game.gb"), Some(Path::new("saves")));
+        assert_eq!(save.path, Path::new("saves/game.sav"));
+    }
+
+    #[test]
+    fn flush_then_load_restores_ram() {
+        let dir = env::temp_dir().join(format!("gbemu-battery-{}", std::process::id()));
+        let save = BatterySave {
+            path: dir.join("game.sav"),
+        };
+        let mut cartridge = Cartridge::from_bytes(build_rom(0x03, 0x01, 0x02)).unwrap();
+        cartridge.write_rom(0x0000, 0x0A);
+        cartridge.write_ram(0xA100, 0x42);
+        assert!(cartridge.ram.dirty);
+        save.flush(&mut cartridge).unwrap();
+        assert!(!cartridge.ram.dirty);
+        assert_eq!(fs::metadata(&save.path).unwrap().len(), 0x2000);
+
+        let mut restored = Cartridge::from_bytes(build_rom(0x03, 0x01, 0x02)).unwrap();
+        assert!(save.load(&mut restored).unwrap());
+        assert_eq!(restored.ram.data[0x100], 0x42);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,353 @@
+use std::fmt;
+
+pub const HEADER_START: usize = 0x0100;
+pub const HEADER_END: usize = 0x014F;
+pub const LOGO_START: usize = 0x0104;
+pub const LOGO_END: usize = 0x0133;
+pub const TITLE_START: usize = 0x0134;
+pub const TITLE_END: usize = 0x0143;
+pub const NEW_LICENSEE_START: usize = 0x0144;
+pub const CGB_FLAG: usize = 0x0143;
+pub const SGB_FLAG: usize = 0x0146;
+pub const CARTRIDGE_TYPE: usize = 0x0147;
+pub const ROM_SIZE: usize = 0x0148;
+pub const RAM_SIZE: usize = 0x0149;
+pub const DESTINATION: usize = 0x014A;
+pub const OLD_LICENSEE: usize = 0x014B;
+pub const VERSION: usize = 0x014C;
+pub const HEADER_CHECKSUM: usize = 0x014D;
+pub const GLOBAL_CHECKSUM: usize = 0x014E;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum MbcKind {
+    RomOnly,
+    Mbc1,
+    Mbc2,
+    Mmm01,
+    Mbc3,
+    Mbc5,
+    Mbc6,
+    Mbc7,
+    PocketCamera,
+    Tama5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mbc: MbcKind,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum CgbSupport {
+    None,
+    Compatible,
+    Only,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Licensee {
+    Old(u8),
+    New(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_banks: usize,
+    pub ram_size: usize,
+    pub japanese: bool,
+    pub licensee: Licensee,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub logo: [u8; 48],
+}
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    TooSmall(usize),
+    UnknownCartridgeType(u8),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum HeaderWarning {
+    LogoMismatch,
+    HeaderChecksumMismatch { expected: u8, actual: u8 },
+    GlobalChecksumMismatch { expected: u16, actual: u16 },
+    RamSizeIgnored(usize),
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Option<CartridgeType> {
+        let (mbc, ram, battery, timer, rumble) = match code {
+            0x00 => (MbcKind::RomOnly, false, false, false, false),
+            0x01 => (MbcKind::Mbc1, false, false, false, false),
+            0x02 => (MbcKind::Mbc1, true, false, false, false),
+            0x03 => (MbcKind::Mbc1, true, true, false, false),
+            0x05 => (MbcKind::Mbc2, false, false, false, false),
+            0x06 => (MbcKind::Mbc2, false, true, false, false),
+            0x08 => (MbcKind::RomOnly, true, false, false, false),
+            0x09 => (MbcKind::RomOnly, true, true, false, false),
+            0x0B => (MbcKind::Mmm01, false, false, false, false),
+            0x0C => (MbcKind::Mmm01, true, false, false, false),
+            0x0D => (MbcKind::Mmm01, true, true, false, false),
+            0x0F => (MbcKind::Mbc3, false, true, true, false),
+            0x10 => (MbcKind::Mbc3, true, true, true, false),
+            0x11 => (MbcKind::Mbc3, false, false, false, false),
+            0x12 => (MbcKind::Mbc3, true, false, false, false),
+            0x13 => (MbcKind::Mbc3, true, true, false, false),
+            0x19 => (MbcKind::Mbc5, false, false, false, false),
+            0x1A => (MbcKind::Mbc5, true, false, false, false),
+            0x1B => (MbcKind::Mbc5, true, true, false, false),
+            0x1C => (MbcKind::Mbc5, false, false, false, true),
+            0x1D => (MbcKind::Mbc5, true, false, false, true),
+            0x1E => (MbcKind::Mbc5, true, true, false, true),
+            0x20 => (MbcKind::Mbc6, true, true, false, false),
+            0x22 => (MbcKind::Mbc7, true, true, false, true),
+            0xFC => (MbcKind::PocketCamera, true, true, false, false),
+            0xFD => (MbcKind::Tama5, true, true, true, false),
+            0xFE => (MbcKind::HuC3, true, true, true, false),
+            0xFF => (MbcKind::HuC1, true, true, false, false),
+            _ => return None,
+        };
+        Some(CartridgeType {
+            code,
+            mbc,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, HeaderError> {
+        if rom.len() <= HEADER_END {
+            return Err(HeaderError::TooSmall(rom.len()));
+        }
+        let cartridge_type = CartridgeType::from_code(rom[CARTRIDGE_TYPE])
+            .ok_or(HeaderError::UnknownCartridgeType(rom[CARTRIDGE_TYPE]))?;
+        let rom_banks = match rom[ROM_SIZE] {
+            // 32 KiB << n, i.e. 2 << n banks of 16 KiB
+            n @ 0x00..=0x08 => 2 << n,
+            0x52 => 72,
+            0x53 => 80,
+            0x54 => 96,
+            n => return Err(HeaderError::UnknownRomSize(n)),
+        };
+        let ram_size = match rom[RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => RAM_BANK_SIZE,
+            0x03 => 4 * RAM_BANK_SIZE,
+            0x04 => 16 * RAM_BANK_SIZE,
+            0x05 => 8 * RAM_BANK_SIZE,
+            n => return Err(HeaderError::UnknownRamSize(n)),
+        };
+        let cgb = match rom[CGB_FLAG] {
+            0xC0 => CgbSupport::Only,
+            0x80 => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        };
+        // Newer carts use the last bytes of the title area for the manufacturer
+        // code and CGB flag, so only the first 15 bytes are title there
+        let title_end = if cgb == CgbSupport::None {
+            TITLE_END
+        } else {
+            TITLE_END - 1
+        };
+        let title = rom[TITLE_START..=title_end]
+            .iter()
+            .take_while(|&&b| b != 0)
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '?'
+                }
+            })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        let licensee = if rom[OLD_LICENSEE] == 0x33 {
+            Licensee::New(
+                rom[NEW_LICENSEE_START..NEW_LICENSEE_START + 2]
+                    .iter()
+                    .map(|&b| b as char)
+                    .collect(),
+            )
+        } else {
+            Licensee::Old(rom[OLD_LICENSEE])
+        };
+        let mut logo = [0; 48];
+        logo.copy_from_slice(&rom[LOGO_START..=LOGO_END]);
+
+        Ok(CartridgeHeader {
+            title,
+            cgb,
+            // The SGB functions are only enabled when the old licensee code is 0x33
+            sgb: rom[SGB_FLAG] == 0x03 && rom[OLD_LICENSEE] == 0x33,
+            cartridge_type,
+            rom_banks,
+            ram_size,
+            japanese: rom[DESTINATION] == 0x00,
+            licensee,
+            version: rom[VERSION],
+            header_checksum: rom[HEADER_CHECKSUM],
+            global_checksum: (rom[GLOBAL_CHECKSUM] as u16) << 8 | rom[GLOBAL_CHECKSUM + 1] as u16,
+            logo,
+        })
+    }
+
+    pub fn rom_size(&self) -> usize {
+        self.rom_banks * ROM_BANK_SIZE
+    }
+
+    pub fn compute_header_checksum(rom: &[u8]) -> u8 {
+        rom[TITLE_START..=VERSION]
+            .iter()
+            .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+    }
+
+    pub fn compute_global_checksum(rom: &[u8]) -> u16 {
+        rom.iter()
+            .enumerate()
+            .filter(|(i, _)| *i != GLOBAL_CHECKSUM && *i != GLOBAL_CHECKSUM + 1)
+            .fold(0u16, |sum, (_, &b)| sum.wrapping_add(b as u16))
+    }
+
+    pub fn validate(&self, rom: &[u8]) -> Vec<HeaderWarning> {
+        let mut warnings = Vec::new();
+        if self.logo != NINTENDO_LOGO {
+            warnings.push(HeaderWarning::LogoMismatch);
+        }
+        let header_checksum = CartridgeHeader::compute_header_checksum(rom);
+        if header_checksum != self.header_checksum {
+            warnings.push(HeaderWarning::HeaderChecksumMismatch {
+                expected: self.header_checksum,
+                actual: header_checksum,
+            });
+        }
+        let global_checksum = CartridgeHeader::compute_global_checksum(rom);
+        if global_checksum != self.global_checksum {
+            warnings.push(HeaderWarning::GlobalChecksumMismatch {
+                expected: self.global_checksum,
+                actual: global_checksum,
+            });
+        }
+        if self.ram_size != 0 && !self.cartridge_type.ram {
+            warnings.push(HeaderWarning::RamSizeIgnored(self.ram_size));
+        }
+        warnings
+    }
+}
+
+impl fmt::Display for MbcKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind_string = match self {
+            MbcKind::RomOnly => "ROM",
+            MbcKind::Mbc1 => "MBC1",
+            MbcKind::Mbc2 => "MBC2",
+            MbcKind::Mmm01 => "MMM01",
+            MbcKind::Mbc3 => "MBC3",
+            MbcKind::Mbc5 => "MBC5",
+            MbcKind::Mbc6 => "MBC6",
+            MbcKind::Mbc7 => "MBC7",
+            MbcKind::PocketCamera => "POCKET CAMERA",
+            MbcKind::Tama5 => "TAMA5",
+            MbcKind::HuC3 => "HuC3",
+            MbcKind::HuC1 => "HuC1",
+        };
+        write!(f, "{}", kind_string)
+    }
+}
+
+impl fmt::Display for CartridgeType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.mbc)?;
+        if self.timer {
+            write!(f, "+TIMER")?;
+        }
+        if self.rumble {
+            write!(f, "+RUMBLE")?;
+        }
+        if self.ram {
+            write!(f, "+RAM")?;
+        }
+        if self.battery {
+            write!(f, "+BATTERY")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Licensee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Licensee::Old(code) => write!(f, "{:#04x}", code),
+            Licensee::New(code) => write!(f, "\"{}\"", code),
+        }
+    }
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderError::TooSmall(len) => write!(
+                f,
+                "image is {} bytes, too small to contain a cartridge header",
+                len
+            ),
+            HeaderError::UnknownCartridgeType(code) => {
+                write!(f, "unknown cartridge type {:#04x}", code)
+            }
+            HeaderError::UnknownRomSize(code) => write!(f, "unknown ROM size code {:#04x}", code),
+            HeaderError::UnknownRamSize(code) => write!(f, "unknown RAM size code {:#04x}", code),
+        }
+    }
+}
+
+impl fmt::Display for HeaderWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderWarning::LogoMismatch => write!(f, "Nintendo logo does not match"),
+            HeaderWarning::HeaderChecksumMismatch { expected, actual } => write!(
+                f,
+                "header checksum is {:#04x} but header says {:#04x}",
+                actual, expected
+            ),
+            HeaderWarning::GlobalChecksumMismatch { expected, actual } => write!(
+                f,
+                "global checksum is {:#06x} but header says {:#06x}",
+                actual, expected
+            ),
+            HeaderWarning::RamSizeIgnored(size) => write!(
+                f,
+                "header declares {} bytes of RAM for a cartridge type without RAM",
+                size
+            ),
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::cartridge::header::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+#[derive(Clone)]
+pub struct Mbc1 {
+    ram_enabled: bool,
+    // 5 bit ROM bank register written at 0x2000-0x3FFF
+    rom_bank: u8,
+    // 2 bit register written at 0x4000-0x5FFF, used as the upper ROM bank
+    // bits or as the RAM bank depending on the cartridge size and mode
+    upper_bank: u8,
+    advanced_mode: bool,
+}
+
+impl Mbc1 {
+    pub fn new() -> Mbc1 {
+        Mbc1 {
+            ram_enabled: false,
+            rom_bank: 1,
+            upper_bank: 0,
+            advanced_mode: false,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                // Bank 0 can never be selected here, writing 0 selects bank 1.
+                // Only the 5 bit value is checked, so on large carts 0x20, 0x40
+                // and 0x60 are unreachable through this window
+                self.rom_bank = match value & 0x1F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.upper_bank = value & 0x3,
+            0x6000..=0x7FFF => self.advanced_mode = value & 0x1 != 0,
+            _ => panic!("Address {:#0x} is not an MBC1 register", address),
+        }
+    }
+
+    pub fn rom_offset(&self, address: u16, rom_banks: usize) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF if self.advanced_mode => (self.upper_bank as usize) << 5,
+            0x0000..=0x3FFF => 0,
+            _ => (self.upper_bank as usize) << 5 | self.rom_bank as usize,
+        };
+        (bank % rom_banks) * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE)
+    }
+
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
+        let bank = if self.advanced_mode {
+            self.upper_bank as usize
+        } else {
+            0
+        };
+        Some(bank * RAM_BANK_SIZE + (address as usize - 0xA000))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn bank_zero_selects_bank_one() {
+        let mut mbc = Mbc1::new();
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000, 128), ROM_BANK_SIZE);
+        mbc.write_register(0x4000, 0x01);
+        mbc.write_register(0x2000, 0x20);
+        assert_eq!(mbc.rom_offset(0x4000, 128), 0x21 * ROM_BANK_SIZE);
+    }
+    #[test]
+    fn rom_bank_is_masked_to_rom_size() {
+        let mut mbc = Mbc1::new();
+        mbc.write_register(0x2000, 0x1F);
+        assert_eq!(mbc.rom_offset(0x4001, 8), 7 * ROM_BANK_SIZE + 1);
+    }
+    #[test]
+    fn advanced_mode_banks_low_window_and_ram() {
+        let mut mbc = Mbc1::new();
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.rom_offset(0x0000, 128), 0);
+        assert_eq!(mbc.ram_offset(0xA000), None);
+        mbc.write_register(0x0000, 0x0A);
+        assert_eq!(mbc.ram_offset(0xA010), Some(0x10));
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.rom_offset(0x0000, 128), 0x40 * ROM_BANK_SIZE);
+        assert_eq!(mbc.ram_offset(0xA010), Some(2 * RAM_BANK_SIZE + 0x10));
+    }
+}
@@ -0,0 +1,69 @@
+use crate::cartridge::header::ROM_BANK_SIZE;
+
+// 512 half-bytes of RAM are built into the MBC2 itself
+pub const MBC2_RAM_SIZE: usize = 0x200;
+
+#[derive(Clone)]
+pub struct Mbc2 {
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl Mbc2 {
+    pub fn new() -> Mbc2 {
+        Mbc2 {
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            // Both registers share 0x0000-0x3FFF, address bit 8 picks which one
+            0x0000..=0x3FFF if address & 0x100 == 0 => self.ram_enabled = value & 0x0F == 0x0A,
+            0x0000..=0x3FFF => {
+                self.rom_bank = match value & 0x0F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x7FFF => {}
+            _ => panic!("Address {:#0x} is not an MBC2 register", address),
+        }
+    }
+
+    pub fn rom_offset(&self, address: u16, rom_banks: usize) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        (bank % rom_banks) * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE)
+    }
+
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
+        // Only 9 address lines are connected so the RAM repeats through 0xBFFF
+        Some((address as usize - 0xA000) & (MBC2_RAM_SIZE - 1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn address_bit_8_selects_register() {
+        let mut mbc = Mbc2::new();
+        mbc.write_register(0x0100, 0x0A);
+        assert_eq!(mbc.ram_offset(0xA000), None);
+        mbc.write_register(0x0000, 0x0A);
+        assert_eq!(mbc.ram_offset(0xA3FF), Some(0x1FF));
+        mbc.write_register(0x2000, 0x05);
+        assert_eq!(mbc.rom_offset(0x4000, 16), 0x0A * ROM_BANK_SIZE);
+        mbc.write_register(0x2100, 0x05);
+        assert_eq!(mbc.rom_offset(0x4000, 16), 5 * ROM_BANK_SIZE);
+        mbc.write_register(0x3F00, 0x10);
+        assert_eq!(mbc.rom_offset(0x4000, 16), ROM_BANK_SIZE);
+    }
+}
@@ -0,0 +1,77 @@
+use crate::cartridge::header::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::cartridge::rtc::{Rtc, RtcClock, RtcRegister};
+use crate::cartridge::RamTarget;
+
+#[derive(Clone)]
+pub struct Mbc3 {
+    ram_enabled: bool,
+    rom_bank: u8,
+    // 0x00-0x07 select a RAM bank, 0x08-0x0C map an RTC register instead
+    ram_bank: u8,
+    pub rtc: Option<Rtc>,
+}
+
+impl Mbc3 {
+    pub fn new(has_timer: bool) -> Mbc3 {
+        Mbc3 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: if has_timer {
+                Some(Rtc::new(RtcClock::Host))
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value & 0x0F == 0x0A,
+            0x2000..=0x3FFF => {
+                self.rom_bank = match value & 0x7F {
+                    0 => 1,
+                    bank => bank,
+                };
+            }
+            0x4000..=0x5FFF => self.ram_bank = value & 0x0F,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(value);
+                }
+            }
+            _ => panic!("Address {:#0x} is not an MBC3 register", address),
+        }
+    }
+
+    pub fn rom_offset(&self, address: u16, rom_banks: usize) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        (bank % rom_banks) * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE)
+    }
+
+    pub fn ram_target(&self, address: u16) -> RamTarget {
+        if !self.ram_enabled {
+            return RamTarget::Unmapped;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (0x00..=0x07, _) => {
+                let offset = address as usize - 0xA000;
+                RamTarget::Ram(self.ram_bank as usize * RAM_BANK_SIZE + offset)
+            }
+            (bank, Some(_)) => match RtcRegister::from_bank(bank) {
+                Some(register) => RamTarget::Rtc(register),
+                None => RamTarget::Unmapped,
+            },
+            (_, None) => RamTarget::Unmapped,
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+}
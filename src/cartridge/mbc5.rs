@@ -0,0 +1,105 @@
+use crate::cartridge::header::{RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RumbleEvent {
+    Start,
+    Stop,
+}
+
+#[derive(Clone)]
+pub struct Mbc5 {
+    ram_enabled: bool,
+    // 9 bit ROM bank, low 8 bits at 0x2000-0x2FFF and bit 8 at 0x3000-0x3FFF
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+    pub rumble_events: Vec<RumbleEvent>,
+}
+
+impl Mbc5 {
+    pub fn new(has_rumble: bool) -> Mbc5 {
+        Mbc5 {
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble: false,
+            rumble_events: Vec::new(),
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = value == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | (value as u16 & 0x1) << 8,
+            0x4000..=0x5FFF => {
+                if self.has_rumble {
+                    // Bit 3 drives the motor on rumble carts, leaving 8 RAM banks
+                    self.set_rumble(value & 0x08 != 0);
+                    self.ram_bank = value & 0x07;
+                } else {
+                    self.ram_bank = value & 0x0F;
+                }
+            }
+            0x6000..=0x7FFF => {}
+            _ => panic!("Address {:#0x} is not an MBC5 register", address),
+        }
+    }
+
+    pub fn rom_offset(&self, address: u16, rom_banks: usize) -> usize {
+        // Unlike MBC1 and MBC3, bank 0 can be mapped into 0x4000-0x7FFF
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+        (bank % rom_banks) * ROM_BANK_SIZE + (address as usize % ROM_BANK_SIZE)
+    }
+
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
+        Some(self.ram_bank as usize * RAM_BANK_SIZE + (address as usize - 0xA000))
+    }
+
+    fn set_rumble(&mut self, rumble: bool) {
+        if rumble != self.rumble {
+            self.rumble = rumble;
+            self.rumble_events.push(if rumble {
+                RumbleEvent::Start
+            } else {
+                RumbleEvent::Stop
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn nine_bit_rom_bank() {
+        let mut mbc = Mbc5::new(false);
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000, 512), 0);
+        mbc.write_register(0x2000, 0x23);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(mbc.rom_offset(0x4000, 512), 0x123 * ROM_BANK_SIZE);
+        assert_eq!(mbc.rom_offset(0x4000, 256), 0x23 * ROM_BANK_SIZE);
+    }
+    #[test]
+    fn rumble_bit_is_reported_once_per_change() {
+        let mut mbc = Mbc5::new(true);
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x0B);
+        mbc.write_register(0x4000, 0x0B);
+        assert_eq!(mbc.ram_offset(0xA000), Some(3 * RAM_BANK_SIZE));
+        mbc.write_register(0x4000, 0x03);
+        assert_eq!(
+            mbc.rumble_events,
+            vec![RumbleEvent::Start, RumbleEvent::Stop]
+        );
+    }
+}
@@ -0,0 +1,310 @@
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The RTC oscillator runs at 32768 Hz, which divides evenly into the
+// 1048576 Hz M-cycle clock
+const CYCLES_PER_SECOND: u32 = 1_048_576;
+const SECONDS_PER_DAY: u64 = 86400;
+// 5 current registers and 5 latched registers as u32s, plus a u64 timestamp.
+// This is the footer layout BGB, VBA-M and mGBA append to .sav files
+pub const RTC_STATE_SIZE: usize = 48;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RtcClock {
+    // Follow the host's wall clock, including time that passed while the
+    // emulator wasn't running
+    Host,
+    // Advance only with emulated cycles so runs are reproducible
+    Cycles,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RtcRegister {
+    Seconds = 0x08,
+    Minutes = 0x09,
+    Hours = 0x0A,
+    DayLow = 0x0B,
+    DayHigh = 0x0C,
+}
+
+#[derive(Clone)]
+pub struct Rtc {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16,
+    halt: bool,
+    carry: bool,
+    latched: [u8; 5],
+    latch_armed: bool,
+    clock: RtcClock,
+    subsecond_cycles: u32,
+    last_sync: u64,
+}
+
+impl RtcRegister {
+    pub fn from_bank(bank: u8) -> Option<RtcRegister> {
+        match bank {
+            0x08 => Some(RtcRegister::Seconds),
+            0x09 => Some(RtcRegister::Minutes),
+            0x0A => Some(RtcRegister::Hours),
+            0x0B => Some(RtcRegister::DayLow),
+            0x0C => Some(RtcRegister::DayHigh),
+            _ => None,
+        }
+    }
+}
+
+impl Rtc {
+    pub fn new(clock: RtcClock) -> Rtc {
+        Rtc {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_armed: false,
+            clock,
+            subsecond_cycles: 0,
+            last_sync: Rtc::host_seconds(),
+        }
+    }
+
+    pub fn set_clock(&mut self, clock: RtcClock) {
+        self.sync();
+        self.clock = clock;
+        self.last_sync = Rtc::host_seconds();
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        if self.clock != RtcClock::Cycles || self.halt {
+            return;
+        }
+        self.subsecond_cycles += cycles;
+        if self.subsecond_cycles >= CYCLES_PER_SECOND {
+            let seconds = self.subsecond_cycles / CYCLES_PER_SECOND;
+            self.subsecond_cycles %= CYCLES_PER_SECOND;
+            self.advance(seconds as u64);
+        }
+    }
+
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.sync();
+            self.latched = self.registers();
+        }
+        self.latch_armed = value == 0x00;
+    }
+
+    pub fn read(&self, register: RtcRegister) -> u8 {
+        self.latched[register as usize - RtcRegister::Seconds as usize]
+    }
+
+    pub fn write(&mut self, register: RtcRegister, value: u8) {
+        self.sync();
+        match register {
+            RtcRegister::Seconds => {
+                self.seconds = value & 0x3F;
+                self.subsecond_cycles = 0;
+            }
+            RtcRegister::Minutes => self.minutes = value & 0x3F,
+            RtcRegister::Hours => self.hours = value & 0x1F,
+            RtcRegister::DayLow => self.days = (self.days & 0x100) | value as u16,
+            RtcRegister::DayHigh => {
+                self.days = (self.days & 0xFF) | ((value as u16 & 0x1) << 8);
+                self.halt = value & 0x40 != 0;
+                self.carry = value & 0x80 != 0;
+            }
+        }
+        // Writes are visible to the game straight away without a new latch
+        self.latched[register as usize - RtcRegister::Seconds as usize] =
+            self.registers()[register as usize - RtcRegister::Seconds as usize];
+    }
+
+    pub fn save(&mut self) -> Vec<u8> {
+        self.sync();
+        let mut data = Vec::with_capacity(RTC_STATE_SIZE);
+        for register in self.registers().iter().chain(self.latched.iter()) {
+            data.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        data.extend_from_slice(&Rtc::host_seconds().to_le_bytes());
+        data
+    }
+
+    pub fn load(&mut self, data: &[u8]) {
+        // Some emulators write a 32 bit timestamp, giving a 44 byte footer
+        if data.len() < 44 {
+            eprintln!("Ignoring RTC state of {} bytes", data.len());
+            return;
+        }
+        let register = |i: usize| data[i * 4];
+        self.seconds = register(0) & 0x3F;
+        self.minutes = register(1) & 0x3F;
+        self.hours = register(2) & 0x1F;
+        self.days = register(3) as u16 | ((register(4) as u16 & 0x1) << 8);
+        self.halt = register(4) & 0x40 != 0;
+        self.carry = register(4) & 0x80 != 0;
+        for i in 0..5 {
+            self.latched[i] = register(i + 5);
+        }
+        let saved_at = if data.len() >= RTC_STATE_SIZE {
+            u64::from_le_bytes(data[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64
+        };
+        self.subsecond_cycles = 0;
+        self.last_sync = saved_at;
+        self.sync();
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            (self.days >> 8) as u8 & 0x1
+                | if self.halt { 0x40 } else { 0 }
+                | if self.carry { 0x80 } else { 0 },
+        ]
+    }
+
+    fn sync(&mut self) {
+        if self.clock != RtcClock::Host {
+            return;
+        }
+        let now = Rtc::host_seconds();
+        if !self.halt && now > self.last_sync {
+            self.advance(now - self.last_sync);
+        }
+        self.last_sync = now;
+    }
+
+    fn advance(&mut self, mut seconds: u64) {
+        // Out of range values written by the game count up to the register's
+        // bit width and wrap to 0 without carrying, so step them one at a time
+        while seconds > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.advance_one_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let total = self.seconds as u64
+            + 60 * self.minutes as u64
+            + 3600 * self.hours as u64
+            + SECONDS_PER_DAY * self.days as u64
+            + seconds;
+        let days = total / SECONDS_PER_DAY;
+        if days > 0x1FF {
+            self.carry = true;
+        }
+        self.days = (days % 0x200) as u16;
+        self.hours = ((total % SECONDS_PER_DAY) / 3600) as u8;
+        self.minutes = ((total % 3600) / 60) as u8;
+        self.seconds = (total % 60) as u8;
+    }
+
+    fn advance_one_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.carry = true;
+        }
+    }
+
+    fn host_seconds() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn counts_emulated_cycles() {
+        let mut rtc = Rtc::new(RtcClock::Cycles);
+        rtc.write(RtcRegister::Hours, 23);
+        rtc.write(RtcRegister::Minutes, 59);
+        rtc.write(RtcRegister::Seconds, 59);
+        rtc.tick(CYCLES_PER_SECOND - 1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RtcRegister::Seconds), 59);
+        rtc.tick(1);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RtcRegister::Seconds), 0);
+        assert_eq!(rtc.read(RtcRegister::Hours), 0);
+        assert_eq!(rtc.read(RtcRegister::DayLow), 1);
+    }
+
+    #[test]
+    fn latch_holds_value_until_relatched() {
+        let mut rtc = Rtc::new(RtcClock::Cycles);
+        latch(&mut rtc);
+        rtc.tick(5 * CYCLES_PER_SECOND);
+        assert_eq!(rtc.read(RtcRegister::Seconds), 0);
+        // A 0x01 write without a preceding 0x00 doesn't latch
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(RtcRegister::Seconds), 0);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RtcRegister::Seconds), 5);
+    }
+
+    #[test]
+    fn halt_stops_the_clock_and_day_overflow_sets_carry() {
+        let mut rtc = Rtc::new(RtcClock::Cycles);
+        rtc.write(RtcRegister::DayHigh, 0x41);
+        rtc.write(RtcRegister::DayLow, 0xFF);
+        rtc.write(RtcRegister::Hours, 23);
+        rtc.write(RtcRegister::Minutes, 59);
+        rtc.write(RtcRegister::Seconds, 59);
+        rtc.tick(2 * CYCLES_PER_SECOND);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RtcRegister::Seconds), 59);
+        rtc.write(RtcRegister::DayHigh, 0x01);
+        rtc.tick(CYCLES_PER_SECOND);
+        latch(&mut rtc);
+        assert_eq!(rtc.read(RtcRegister::DayLow), 0);
+        assert_eq!(rtc.read(RtcRegister::DayHigh), 0x80);
+    }
+
+    #[test]
+    fn state_round_trips() {
+        let mut rtc = Rtc::new(RtcClock::Cycles);
+        rtc.write(RtcRegister::Minutes, 42);
+        rtc.write(RtcRegister::DayHigh, 0x01);
+        latch(&mut rtc);
+        let state = rtc.save();
+        assert_eq!(state.len(), RTC_STATE_SIZE);
+        let mut restored = Rtc::new(RtcClock::Cycles);
+        restored.load(&state);
+        assert_eq!(restored.read(RtcRegister::Minutes), 42);
+        assert_eq!(restored.read(RtcRegister::DayHigh), 0x01);
+    }
+}
@@ -0,0 +1,366 @@
+use minifb::Key;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::joypad::Button;
+use crate::palette::{self, Palette};
+
+// Frontend actions that can be bound to keys alongside the Game Boy buttons
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Action {
+    Quit,
+    Pause,
+    // Held rather than toggled
+    FastForward,
+    Reset,
+    Screenshot,
+    SaveState,
+    LoadState,
+    NextSlot,
+    // Runs a single frame and leaves the emulator paused
+    FrameAdvance,
+    NextPalette,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Binding {
+    Button(Button),
+    Action(Action),
+}
+
+// Names used in the config file, in the order bindings are listed
+const BINDING_NAMES: [(&str, Binding); 18] = [
+    ("up", Binding::Button(Button::Up)),
+    ("down", Binding::Button(Button::Down)),
+    ("left", Binding::Button(Button::Left)),
+    ("right", Binding::Button(Button::Right)),
+    ("a", Binding::Button(Button::A)),
+    ("b", Binding::Button(Button::B)),
+    ("select", Binding::Button(Button::Select)),
+    ("start", Binding::Button(Button::Start)),
+    ("quit", Binding::Action(Action::Quit)),
+    ("pause", Binding::Action(Action::Pause)),
+    ("fast_forward", Binding::Action(Action::FastForward)),
+    ("reset", Binding::Action(Action::Reset)),
+    ("screenshot", Binding::Action(Action::Screenshot)),
+    ("save_state", Binding::Action(Action::SaveState)),
+    ("load_state", Binding::Action(Action::LoadState)),
+    ("next_slot", Binding::Action(Action::NextSlot)),
+    ("frame_advance", Binding::Action(Action::FrameAdvance)),
+    ("next_palette", Binding::Action(Action::NextPalette)),
+];
+
+const DEFAULT_BINDINGS: [(Key, Binding); 18] = [
+    (Key::Up, Binding::Button(Button::Up)),
+    (Key::Down, Binding::Button(Button::Down)),
+    (Key::Left, Binding::Button(Button::Left)),
+    (Key::Right, Binding::Button(Button::Right)),
+    (Key::X, Binding::Button(Button::A)),
+    (Key::Z, Binding::Button(Button::B)),
+    (Key::Backspace, Binding::Button(Button::Select)),
+    (Key::Enter, Binding::Button(Button::Start)),
+    (Key::Escape, Binding::Action(Action::Quit)),
+    (Key::P, Binding::Action(Action::Pause)),
+    (Key::Tab, Binding::Action(Action::FastForward)),
+    (Key::R, Binding::Action(Action::Reset)),
+    (Key::F12, Binding::Action(Action::Screenshot)),
+    (Key::F5, Binding::Action(Action::SaveState)),
+    (Key::F7, Binding::Action(Action::LoadState)),
+    (Key::F6, Binding::Action(Action::NextSlot)),
+    (Key::N, Binding::Action(Action::FrameAdvance)),
+    (Key::F8, Binding::Action(Action::NextPalette)),
+];
+
+macro_rules! key_names {
+    ($($key:ident),*) => {
+        const KEY_NAMES: &[(&str, Key)] = &[$((stringify!($key), Key::$key)),*];
+    };
+}
+
+#[rustfmt::skip]
+key_names!(
+    Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9, A, B, C, D, E, F, G, H, I, J, K, L,
+    M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z, F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    F13, F14, F15, Down, Left, Right, Up, Apostrophe, Backquote, Backslash, Comma, Equal,
+    LeftBracket, Minus, Period, RightBracket, Semicolon, Slash, Backspace, Delete, End, Enter,
+    Escape, Home, Insert, Menu, PageDown, PageUp, Pause, Space, Tab, NumLock, CapsLock, ScrollLock,
+    LeftShift, RightShift, LeftCtrl, RightCtrl, NumPad0, NumPad1, NumPad2, NumPad3, NumPad4,
+    NumPad5, NumPad6, NumPad7, NumPad8, NumPad9, NumPadDot, NumPadSlash, NumPadAsterisk,
+    NumPadMinus, NumPadPlus, NumPadEnter, LeftAlt, RightAlt, LeftSuper, RightSuper
+);
+
+// Key names as spelled in minifb's Key enum, ignoring case. Digits can be
+// written without the Key prefix
+pub fn parse_key(name: &str) -> Option<Key> {
+    let name = if name.len() == 1 && name.as_bytes()[0].is_ascii_digit() {
+        format!("Key{}", name)
+    } else {
+        name.to_string()
+    };
+    KEY_NAMES
+        .iter()
+        .find(|(key_name, _)| key_name.eq_ignore_ascii_case(&name))
+        .map(|(_, key)| *key)
+}
+
+// A # starts a comment. With colors, a # partway through the line followed by
+// a hex digit is a colour like #e0f8d0 instead
+fn strip_comment(line: &str, colors: bool) -> &str {
+    for (index, c) in line.char_indices() {
+        let color = colors
+            && !line[..index].trim().is_empty()
+            && line
+                .as_bytes()
+                .get(index + 1)
+                .is_some_and(u8::is_ascii_hexdigit);
+        if c == '#' && !color {
+            return &line[..index];
+        }
+    }
+    line
+}
+
+fn is_palette_setting(name: &str) -> bool {
+    name.eq_ignore_ascii_case("palette")
+        || name
+            .get(..8)
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case("palette."))
+}
+
+// Settings read from a text file of `name = value` lines, where # starts a
+// comment. Bindings take a comma separated list of keys, e.g. `a = X, K`.
+// `palette.NAME = ` followed by four hex colours adds a palette, and
+// `palette = NAME` picks the one to start with. On those two lines a comment
+// can't start with a hex digit, since #e0f8d0 is a colour there
+pub struct Config {
+    pub bindings: Vec<(Key, Binding)>,
+    pub palette: Option<String>,
+    pub custom_palettes: Vec<(String, Palette)>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            bindings: DEFAULT_BINDINGS.to_vec(),
+            palette: None,
+            custom_palettes: Vec::new(),
+        }
+    }
+
+    // $XDG_CONFIG_HOME/gbemu/config, falling back to ~/.config/gbemu/config
+    pub fn default_path() -> Option<PathBuf> {
+        let config_home = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+        };
+        Some(config_home.join("gbemu").join("config"))
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("could not read config {}: {}", path.display(), e))?;
+        Config::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // Anything the file binds replaces its defaults, and keys it uses are
+    // taken away from the default bindings so one key never does two things
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::new();
+        let mut palette_line = 0;
+        let mut assigned: Vec<(Binding, Vec<Key>)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let stripped = strip_comment(line, false).trim();
+            if stripped.is_empty() {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {}", number + 1, message);
+            let index = stripped
+                .find('=')
+                .ok_or_else(|| error(format!("expected name = value, got \"{}\"", stripped)))?;
+            let name = stripped[..index].trim();
+            let line = if is_palette_setting(name) {
+                strip_comment(line, true).trim()
+            } else {
+                stripped
+            };
+            let value = line[index + 1..].trim();
+            if name.eq_ignore_ascii_case("palette") {
+                config.palette = Some(value.to_string());
+                palette_line = number + 1;
+                continue;
+            }
+            if is_palette_setting(name) && name.len() > 8 {
+                let palette = palette::parse_hex(value).map_err(error)?;
+                let palette_name = name[8..].trim().to_string();
+                config
+                    .custom_palettes
+                    .retain(|(other, _)| !other.eq_ignore_ascii_case(&palette_name));
+                config.custom_palettes.push((palette_name, palette));
+                continue;
+            }
+            let binding = BINDING_NAMES
+                .iter()
+                .find(|(binding_name, _)| binding_name.eq_ignore_ascii_case(name))
+                .map(|(_, binding)| *binding)
+                .ok_or_else(|| error(format!("unknown setting \"{}\"", name)))?;
+            let mut keys = Vec::new();
+            for key_name in value.split(',').map(str::trim).filter(|k| !k.is_empty()) {
+                let key = parse_key(key_name)
+                    .ok_or_else(|| error(format!("unknown key \"{}\"", key_name)))?;
+                keys.push(key);
+            }
+            assigned.retain(|(other, _)| *other != binding);
+            assigned.push((binding, keys));
+        }
+
+        // Checked last since the palette can be defined further down the file
+        if let Some(name) = &config.palette {
+            config
+                .find_palette(name)
+                .map_err(|e| format!("line {}: {}", palette_line, e))?;
+        }
+
+        config.bindings.clear();
+        for (_, binding) in BINDING_NAMES.iter() {
+            match assigned.iter().find(|(other, _)| other == binding) {
+                Some((_, keys)) => config
+                    .bindings
+                    .extend(keys.iter().map(|key| (*key, *binding))),
+                None => config.bindings.extend(
+                    DEFAULT_BINDINGS
+                        .iter()
+                        .filter(|(key, other)| {
+                            other == binding && !assigned.iter().any(|(_, keys)| keys.contains(key))
+                        })
+                        .cloned(),
+                ),
+            }
+        }
+        Ok(config)
+    }
+
+    // Palettes from the file, then presets, then a list of hex colours
+    pub fn find_palette(&self, name: &str) -> Result<Palette, String> {
+        match self
+            .custom_palettes
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+        {
+            Some((_, palette)) => Ok(*palette),
+            None => palette::from_name(name),
+        }
+    }
+
+    // The presets and then the file's own, in the order the hotkey steps
+    // through them
+    pub fn palettes(&self) -> Vec<(String, Palette)> {
+        palette::PRESETS
+            .iter()
+            .map(|(name, palette)| (name.to_string(), *palette))
+            .chain(self.custom_palettes.iter().cloned())
+            .collect()
+    }
+
+    pub fn keys_for(&self, binding: Binding) -> impl Iterator<Item = Key> + '_ {
+        self.bindings
+            .iter()
+            .filter(move |(_, other)| *other == binding)
+            .map(|(key, _)| *key)
+    }
+
+    // Every button once, whether or not it has a key
+    pub fn buttons() -> impl Iterator<Item = Button> {
+        BINDING_NAMES
+            .iter()
+            .filter_map(|(_, binding)| match binding {
+                Binding::Button(button) => Some(*button),
+                Binding::Action(_) => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_key_names() {
+        assert_eq!(parse_key("enter"), Some(Key::Enter));
+        assert_eq!(parse_key("NumPad5"), Some(Key::NumPad5));
+        assert_eq!(parse_key("7"), Some(Key::Key7));
+        assert_eq!(parse_key("Hyper"), None);
+    }
+
+    #[test]
+    fn file_bindings_replace_defaults() {
+        let config =
+            Config::parse("# swap A and B\na = Z, K\nb = x\n\npause = Space # toggle\n").unwrap();
+        let keys = |binding| config.keys_for(binding).collect::<Vec<Key>>();
+        assert_eq!(keys(Binding::Button(Button::A)), vec![Key::Z, Key::K]);
+        assert_eq!(keys(Binding::Button(Button::B)), vec![Key::X]);
+        assert_eq!(keys(Binding::Action(Action::Pause)), vec![Key::Space]);
+        assert_eq!(keys(Binding::Button(Button::Start)), vec![Key::Enter]);
+    }
+
+    #[test]
+    fn comments_can_follow_without_a_space() {
+        let config = Config::parse("pause = Space #fast\na = X #1\n#f00 = Y").unwrap();
+        assert_eq!(
+            config.keys_for(Binding::Action(Action::Pause)).next(),
+            Some(Key::Space)
+        );
+        assert_eq!(
+            config.keys_for(Binding::Button(Button::A)).next(),
+            Some(Key::X)
+        );
+    }
+
+    #[test]
+    fn file_keys_are_taken_from_other_defaults() {
+        let config = Config::parse("screenshot = R").unwrap();
+        assert_eq!(config.keys_for(Binding::Action(Action::Reset)).count(), 0);
+        assert_eq!(
+            config.keys_for(Binding::Action(Action::Screenshot)).next(),
+            Some(Key::R)
+        );
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(
+            Config::parse("a = X\nturbo = T").err().unwrap(),
+            "line 2: unknown setting \"turbo\""
+        );
+        assert_eq!(
+            Config::parse("a = Hyper").err().unwrap(),
+            "line 1: unknown key \"Hyper\""
+        );
+        assert!(Config::parse("a X").is_err());
+    }
+
+    #[test]
+    fn custom_palettes() {
+        let config = Config::parse(
+            "palette = dmg # start with it\n#abc\n\
+             palette.dmg = #e0f8d0, #88c070, #346856, #081820 #mine\nnext_palette = C",
+        )
+        .unwrap();
+        assert_eq!(
+            config.find_palette(config.palette.as_ref().unwrap()),
+            Ok([0x00e0f8d0, 0x0088c070, 0x00346856, 0x00081820])
+        );
+        assert_eq!(config.find_palette("pocket"), Ok(palette::GRAYSCALE));
+        let palettes = config.palettes();
+        assert_eq!(palettes.len(), palette::PRESETS.len() + 1);
+        assert_eq!(palettes.last().unwrap().0, "dmg");
+        assert_eq!(
+            config.keys_for(Binding::Action(Action::NextPalette)).next(),
+            Some(Key::C)
+        );
+
+        assert!(Config::parse("palette = sepia").is_err());
+        assert!(Config::parse("palette.bad = #ffffff").is_err());
+        assert!(Config::parse("palette→x = #ffffff").is_err());
+    }
+}
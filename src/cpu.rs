@@ -1,6 +1,4 @@
-use std::process;
-
-mod instruction;
+pub mod instruction;
 pub mod interrupt_handler;
 mod registers;
 use crate::cpu::instruction::*;
@@ -8,31 +6,77 @@ use crate::cpu::interrupt_handler::*;
 use crate::cpu::registers::*;
 use crate::gb;
 use crate::memory::Memory;
+use crate::model::{self, Model};
+use crate::serial;
 use crate::timer::Cycles;
 
+#[derive(Clone)]
 pub struct Cpu {
     registers: Registers,
     pc: u16,
     sp: u16,
     finished_bootrom: bool,
+    halted: bool,
+    halt_bug: bool,
+    stopped: bool,
+    // M-cycles left before the CPU resumes after a CGB speed switch
+    speed_switch_cycles: u16,
     pub memory: Memory,
     pub interrupt_handler: InterruptHandler,
     _current_instruction: (Instruction, u8),
 }
 
+// The CPU idles for 2050 M-cycles while the CGB's clock changes speed
+const SPEED_SWITCH_CYCLES: u16 = 2050;
+
 impl Cpu {
-    pub fn new() -> Cpu {
-        let mut memory = Memory::initialize();
+    pub fn new(mut memory: Memory) -> Cpu {
         memory.write_byte(gb::lcd_stat, 0x02);
-        Cpu {
+        let mut cpu = Cpu {
             registers: Registers::new(),
-            pc: 0, //gb::init_pc_value,
+            pc: 0,
             sp: 0,
             memory,
-            interrupt_handler: InterruptHandler { ime: false },
+            interrupt_handler: InterruptHandler::new(),
             finished_bootrom: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            speed_switch_cycles: 0,
             _current_instruction: (Instruction::Nop, 0),
+        };
+        if !cpu.memory.bootrom_mapped {
+            cpu.skip_bootrom(cpu.memory.model);
+        }
+        cpu
+    }
+
+    fn skip_bootrom(&mut self, model: Model) {
+        let registers = model.post_boot_registers(&self.memory.cartridge);
+        self.registers.set_16bit(&RegisterPair::Af, registers.af);
+        self.registers.set_16bit(&RegisterPair::Bc, registers.bc);
+        self.registers.set_16bit(&RegisterPair::De, registers.de);
+        self.registers.set_16bit(&RegisterPair::Hl, registers.hl);
+        self.sp = gb::init_sp_value;
+        self.pc = gb::init_pc_value;
+        let io = model.post_boot_io();
+        for (address, value) in &io {
+            self.memory.io[(address - 0xFF00) as usize] = *value;
         }
+        self.memory.apu.skip_bootrom(&io);
+        let div = self.memory.io[(gb::div_addr - 0xFF00) as usize];
+        self.memory.timer.set_divider((div as u16) << 8);
+        let sc = self.memory.io[(serial::SC - 0xFF00) as usize];
+        self.memory.serial.write(serial::SC, sc);
+        if model.draws_logo() {
+            model::draw_logo(&mut self.memory.vram, &self.memory.cartridge.header.logo);
+        }
+        self.finished_bootrom = true;
+    }
+
+    // In STOP mode the LCD is off along with the CPU
+    pub fn stopped(&self) -> bool {
+        self.stopped
     }
 
     pub fn step(&mut self) -> u8 {
@@ -40,18 +84,68 @@ impl Cpu {
             self.memory.replace_bootrom();
             self.finished_bootrom = true;
         }
-        if self.interrupt_handler.interrupts_enabled() {
-            if let Some(interrupt) = self.interrupt_handler.check_interrupts(&mut self.memory) {
-                self.interrupt_handler.disable_interrupts();
-                self.call(interrupt_handler::address_for_interrupt(interrupt));
+        if self.stopped {
+            // The system clock is stopped, so nothing else advances either
+            if !self.memory.joypad_line_low() {
+                return 1;
+            }
+            self.stopped = false;
+        }
+        if self.speed_switch_cycles > 0 {
+            self.speed_switch_cycles -= 1;
+            self.tick(1);
+            return 1;
+        }
+        if self.halted {
+            if !self.interrupt_handler.interrupt_pending(&self.memory) {
+                // The clock keeps running for the PPU and timers while halted
+                self.tick(1);
+                return 1;
             }
+            self.halted = false;
         }
-        let instruction = Instruction::from_bytes(&self.memory, self.pc);
+        if self.interrupt_handler.interrupts_enabled()
+            && self.interrupt_handler.interrupt_pending(&self.memory)
+        {
+            self.dispatch_interrupt();
+            self.tick(DISPATCH_CYCLES);
+            return DISPATCH_CYCLES;
+        }
+        self.interrupt_handler.apply_scheduled_enable();
+        let instruction = if self.halt_bug {
+            // PC isn't incremented after the opcode fetch, so its byte is read
+            // again as the first operand or the next opcode
+            self.halt_bug = false;
+            let instruction = Instruction::from_bytes_split(&self.memory, self.pc, self.pc);
+            self.pc = self.pc.wrapping_sub(1);
+            instruction
+        } else {
+            Instruction::from_bytes(&self.memory, self.pc)
+        };
         // println!(
         //     "{:#0x}: {}, {}, sp: {:#0x}",
         //     self.pc, instruction, self.registers, self.sp
         // );
-        self.execute(&instruction)
+        let cycles = self.execute(&instruction);
+        self.tick(cycles);
+        cycles
+    }
+
+    // Advance the rest of the system and raise the interrupts it requested
+    fn tick(&mut self, cycles: u8) {
+        self.memory.tick(cycles as u32);
+        if self.memory.timer.take_interrupt() {
+            self.interrupt_handler
+                .set_interrupt(&mut self.memory, Interrupt::Timer);
+        }
+        if self.memory.joypad.take_interrupt() {
+            self.interrupt_handler
+                .set_interrupt(&mut self.memory, Interrupt::Joypad);
+        }
+        if self.memory.serial.take_interrupt() {
+            self.interrupt_handler
+                .set_interrupt(&mut self.memory, Interrupt::Serial);
+        }
     }
 
     fn execute(&mut self, i: &Instruction) -> u8 {
@@ -60,12 +154,23 @@ impl Cpu {
         match i {
             Instruction::Nop => {}
             Instruction::Stop => {
-                println!("Stopping program with instruction {}", i);
-                process::exit(1);
+                self.memory.reset_div();
+                if self.memory.try_speed_switch() {
+                    self.speed_switch_cycles = SPEED_SWITCH_CYCLES;
+                } else {
+                    self.stopped = true;
+                }
             }
             Instruction::Halt => {
-                println!("Halting program with instruction {}", i);
-                process::exit(0);
+                // With IME clear and an interrupt already pending HALT exits
+                // straight away and triggers the HALT bug on the DMG
+                if !self.interrupt_handler.interrupts_enabled()
+                    && self.interrupt_handler.interrupt_pending(&self.memory)
+                {
+                    self.halt_bug = true;
+                } else {
+                    self.halted = true;
+                }
             }
             Instruction::SetCarryFlag => {
                 self.registers.set_flag(Flag::Carry, true);
@@ -557,7 +662,6 @@ impl Cpu {
                 let mut nybble_1 = a & 0xF;
                 let mut nybble_2 = ((a & 0xF0) >> 4) & 0xF;
                 let a_adjusted;
-                println!("Adjusting A: {:#x}", a);
                 if self.registers.get_flag(Flag::Subtract) {
                     if nybble_1 > 0x9 || self.registers.get_flag(Flag::HalfCarry) {
                         nybble_1 -= 0x6;
@@ -704,19 +808,16 @@ impl Cpu {
                         }
                     }
                 },
+                // Unlike EI, RETI enables interrupts without a delay
                 ReturnKind::ReturnInterrupt => {
                     self.pop(&Load16Target::StackPointer);
-                    println!("enabling interrupts through reti");
                     self.interrupt_handler.enable_interrupts();
                 }
             },
             Instruction::Pop(reg_pair) => self.pop(&Load16Target::Register16(*reg_pair)),
             Instruction::Push(reg_pair) => self.push(self.registers.get_16bit(reg_pair)),
             Instruction::DisableInterrupts => self.interrupt_handler.disable_interrupts(),
-            Instruction::EnableInterrupts => {
-                println!("Enabling ints through ei");
-                self.interrupt_handler.enable_interrupts()
-            }
+            Instruction::EnableInterrupts => self.interrupt_handler.schedule_enable(),
             Instruction::Call(kind) => match kind {
                 CallKind::Call(a16) => {
                     self.call(*a16);
@@ -786,10 +887,25 @@ impl Cpu {
         self.pc = address;
     }
 
+    fn dispatch_interrupt(&mut self) {
+        self.interrupt_handler.disable_interrupts();
+        self.sp = self.sp.wrapping_sub(1);
+        self.memory.write_byte(self.sp, (self.pc >> 8) as u8);
+        // The interrupt is only picked after the high byte is pushed, so a push
+        // that lands on IE can change it or cancel dispatch and jump to 0x0000
+        let address = match self.interrupt_handler.check_interrupts(&mut self.memory) {
+            Some(interrupt) => interrupt_handler::address_for_interrupt(interrupt),
+            None => 0x0000,
+        };
+        self.sp = self.sp.wrapping_sub(1);
+        self.memory.write_byte(self.sp, self.pc as u8);
+        self.pc = address;
+    }
+
     fn pop(&mut self, target: &Load16Target) {
-        let low_byte = self.memory.read_byte(self.sp + 1);
-        let high_byte = self.memory.read_byte(self.sp + 2);
-        self.sp += 2;
+        let low_byte = self.memory.read_byte(self.sp);
+        let high_byte = self.memory.read_byte(self.sp.wrapping_add(1));
+        self.sp = self.sp.wrapping_add(2);
         let val = low_byte as u16 | ((high_byte as u16) << 8);
         if let Load16Target::StackPointer = target {
             self.pc = val;
@@ -802,9 +918,10 @@ impl Cpu {
     fn push(&mut self, val: u16) {
         let low_byte = val & 0xFF;
         let high_byte = (val >> 8) & 0xFF;
+        self.sp = self.sp.wrapping_sub(1);
         self.memory.write_byte(self.sp, high_byte as u8);
-        self.memory.write_byte(self.sp - 1, low_byte as u8);
-        self.sp -= 2;
+        self.sp = self.sp.wrapping_sub(1);
+        self.memory.write_byte(self.sp, low_byte as u8);
     }
 
     fn execute_instruction_16(&mut self, instruction: &Instruction16) {
@@ -970,7 +1087,7 @@ impl Cpu {
 
     fn dump(&self) {
         println!("ROM:");
-        println!("{:?}", self.memory.rom_bank0.iter().enumerate());
+        println!("{:?}", self.memory.cartridge.rom.iter().enumerate());
         println!("VRAM:");
         println!("{:?}", self.memory.vram);
     }
@@ -979,9 +1096,11 @@ impl Cpu {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::joypad::Button;
+    use crate::memory::test::{blank_memory, dmg_bootrom_memory};
     #[test]
     fn execute_load16() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.execute(&Instruction::Load16(
             Load16Target::Register16(RegisterPair::Hl),
             Load16Source::Data(0xAB),
@@ -990,7 +1109,7 @@ mod test {
     }
     #[test]
     fn execute_load16_sp() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.execute(&Instruction::Load16(
             Load16Target::Register16(RegisterPair::Hl),
             Load16Source::Data(0xAB),
@@ -1003,7 +1122,7 @@ mod test {
     }
     #[test]
     fn execute_daa_after_sub() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.registers.set(&Register::A, 0x47);
         cpu.registers.set(&Register::D, 0x28);
         cpu.execute(&Instruction::Sub(ArithmeticOperand::Register(Register::D)));
@@ -1012,7 +1131,7 @@ mod test {
     }
     #[test]
     fn execute_daa_after_add() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.registers.set(&Register::A, 0x47);
         cpu.registers.set(&Register::D, 0x28);
         cpu.execute(&Instruction::Add(ArithmeticOperand::Register(Register::D)));
@@ -1021,7 +1140,7 @@ mod test {
     }
     #[test]
     fn check_flags_after_bit() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.registers.set(&Register::A, 0x47);
         cpu.execute(&Instruction::Load16(
             Load16Target::Register16(RegisterPair::Hl),
@@ -1036,8 +1155,141 @@ mod test {
         assert_eq!(true, cpu.registers.get_flag(Flag::HalfCarry));
     }
     #[test]
+    fn halt_waits_for_an_interrupt() {
+        let mut cpu = Cpu::new(blank_memory());
+        cpu.memory.replace_bootrom();
+        cpu.finished_bootrom = true;
+        cpu.pc = 0xC000;
+        cpu.memory.write_byte(0xC000, 0x76);
+        cpu.memory.write_byte(0xC001, 0x3C);
+        cpu.memory.write_byte(gb::ie, 0x04);
+        cpu.step();
+        assert_eq!(1, cpu.step());
+        assert_eq!(0xC001, cpu.pc);
+        // With IME clear the CPU carries on without servicing the interrupt
+        cpu.memory.write_byte(gb::iflags, 0x04);
+        cpu.step();
+        assert_eq!(0xC002, cpu.pc);
+        assert_eq!(0x04, cpu.memory.read_byte(gb::iflags) & 0x1F);
+    }
+    #[test]
+    fn halt_bug_reads_next_byte_twice() {
+        let mut cpu = Cpu::new(blank_memory());
+        cpu.memory.replace_bootrom();
+        cpu.finished_bootrom = true;
+        cpu.pc = 0xC000;
+        cpu.registers.set(&Register::A, 0);
+        cpu.registers.set(&Register::D, 0);
+        // HALT; LD A,0x14 runs as LD A,0x3E; INC D
+        for (i, byte) in [0x76, 0x3E, 0x14].iter().enumerate() {
+            cpu.memory.write_byte(0xC000 + i as u16, *byte);
+        }
+        cpu.memory.write_byte(gb::ie, 0x01);
+        cpu.memory.write_byte(gb::iflags, 0x01);
+        cpu.step();
+        cpu.step();
+        assert_eq!(0x3E, cpu.registers.get(&Register::A));
+        cpu.step();
+        assert_eq!(0x01, cpu.registers.get(&Register::D));
+        assert_eq!(0xC003, cpu.pc);
+    }
+    #[test]
+    fn stop_waits_for_joypad_and_resets_div() {
+        let mut cpu = Cpu::new(blank_memory());
+        cpu.memory.replace_bootrom();
+        cpu.finished_bootrom = true;
+        cpu.pc = 0xC000;
+        cpu.memory.write_byte(0xC000, 0x10);
+        cpu.memory.timer.set_divider(0xAB00);
+        assert_eq!(0xAB, cpu.memory.read_byte(gb::div_addr));
+        cpu.memory.write_byte(gb::joypad, 0x20);
+        cpu.step();
+        assert!(cpu.stopped());
+        assert_eq!(0x00, cpu.memory.read_byte(gb::div_addr));
+        cpu.step();
+        assert_eq!(0xC002, cpu.pc);
+        // Holding a button in the selected row pulls a line low
+        cpu.memory.joypad.set_button(Button::Right, true);
+        cpu.step();
+        assert!(!cpu.stopped());
+        assert_eq!(0xC003, cpu.pc);
+    }
+    #[test]
+    fn stop_switches_cgb_speed_when_armed() {
+        let mut memory = blank_memory();
+        memory.model = Model::Cgb;
+        memory.io[0x4D] = 0x7E;
+        let mut cpu = Cpu::new(memory);
+        cpu.memory.write_byte(gb::key1_addr, 0x01);
+        cpu.execute(&Instruction::Stop);
+        assert!(!cpu.stopped());
+        assert!(cpu.memory.double_speed());
+        assert_eq!(0xFE, cpu.memory.read_byte(gb::key1_addr));
+    }
+    fn run_from_wram(program: &[u8]) -> Cpu {
+        let mut cpu = Cpu::new(blank_memory());
+        cpu.memory.replace_bootrom();
+        cpu.finished_bootrom = true;
+        cpu.pc = 0xC000;
+        cpu.sp = 0xD000;
+        for (i, byte) in program.iter().enumerate() {
+            cpu.memory.write_byte(0xC000 + i as u16, *byte);
+        }
+        cpu
+    }
+    #[test]
+    fn ei_takes_effect_after_next_instruction() {
+        // EI; NOP; NOP
+        let mut cpu = run_from_wram(&[0xFB, 0x00, 0x00]);
+        cpu.memory.write_byte(gb::ie, 0x01);
+        cpu.memory.write_byte(gb::iflags, 0x01);
+        cpu.step();
+        cpu.step();
+        assert_eq!(0xC002, cpu.pc);
+        assert_eq!(5, cpu.step());
+        assert_eq!(0x0040, cpu.pc);
+        assert_eq!(0x00, cpu.memory.read_byte(gb::iflags) & 0x1F);
+        assert_eq!(0xC002, cpu.memory.read_2_bytes(cpu.sp));
+    }
+    #[test]
+    fn di_straight_after_ei_blocks_interrupts() {
+        // EI; DI; NOP
+        let mut cpu = run_from_wram(&[0xFB, 0xF3, 0x00]);
+        cpu.memory.write_byte(gb::ie, 0x01);
+        cpu.memory.write_byte(gb::iflags, 0x01);
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert_eq!(0xC003, cpu.pc);
+    }
+    #[test]
+    fn reti_enables_interrupts_immediately() {
+        // RETI
+        let mut cpu = run_from_wram(&[0xD9]);
+        cpu.push(0xC100);
+        cpu.memory.write_byte(gb::ie, 0x04);
+        cpu.memory.write_byte(gb::iflags, 0x04);
+        cpu.step();
+        assert_eq!(0xC100, cpu.pc);
+        cpu.step();
+        assert_eq!(0x0050, cpu.pc);
+    }
+    #[test]
+    fn pushing_over_ie_cancels_dispatch() {
+        let mut cpu = run_from_wram(&[0x00]);
+        cpu.pc = 0x0200;
+        cpu.sp = 0x0000;
+        cpu.interrupt_handler.enable_interrupts();
+        cpu.memory.write_byte(gb::ie, 0x01);
+        cpu.memory.write_byte(gb::iflags, 0x01);
+        // The high byte of PC, 0x02, disables VBlank as it's pushed into IE
+        cpu.step();
+        assert_eq!(0x0000, cpu.pc);
+        assert_eq!(0x01, cpu.memory.read_byte(gb::iflags) & 0x1F);
+    }
+    #[test]
     fn rotate_left_logical() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.registers.set(&Register::C, 0xCE);
         cpu.execute(&Instruction::Instruction16(Instruction16::RotateLeft(
             ArithmeticOperand::Register(Register::C),
@@ -1047,7 +1299,7 @@ mod test {
     }
     #[test]
     fn rotate_left_arithmetic() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.registers.set_flag(Flag::Carry, true);
         cpu.registers.set(&Register::A, 0xCE);
         cpu.execute(&Instruction::Rotate(RotateKind::Left));
@@ -1056,7 +1308,7 @@ mod test {
     }
     #[test]
     fn execute_push_pop() {
-        let mut cpu = Cpu::new();
+        let mut cpu = Cpu::new(blank_memory());
         cpu.sp = 0xFFFC;
         cpu.execute(&Instruction::Load16(
             Load16Target::Register16(RegisterPair::Hl),
@@ -1074,7 +1326,7 @@ mod test {
 
     #[test]
     pub fn output_bootrom() {
-        let cpu = Cpu::new();
+        let cpu = Cpu::new(dmg_bootrom_memory());
         let mut bytes_read = 0;
         while bytes_read < 0x00a7 {
             let instruction = Instruction::from_bytes(&cpu.memory, bytes_read);
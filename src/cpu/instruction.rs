@@ -5,7 +5,12 @@ use crate::cpu::registers::RegisterPair;
 use crate::memory::Memory;
 use crate::timer;
 
-#[derive(Debug, PartialEq)]
+// Opcodes with no instruction, which lock up the CPU on hardware
+pub const ILLEGAL_OPCODES: [u8; 11] = [
+    0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+];
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum Instruction {
     Nop,
     Stop,
@@ -85,6 +90,12 @@ impl fmt::Display for Instruction {
 
 impl Instruction {
     pub fn from_bytes(memory: &Memory, a: u16) -> Instruction {
+        Instruction::from_bytes_split(memory, a, a + 1)
+    }
+
+    // Decode with the operands read from `operands` rather than straight after
+    // the opcode, which is what the HALT bug does
+    pub fn from_bytes_split(memory: &Memory, a: u16, operands: u16) -> Instruction {
         let byte = memory.read_byte(a);
         let high_bits = (byte & 0xF0) >> 4;
         let low_bits = byte & 0x0F;
@@ -92,7 +103,7 @@ impl Instruction {
             (0x0, 0x0) => Instruction::Nop,
             (0x1, 0x0) => Instruction::Stop,
             (0x2..=0x3, 0x0) => {
-                let offset = memory.read_byte(a + 1);
+                let offset = memory.read_byte(operands);
                 match high_bits {
                     0x2 => Instruction::Jump(JumpKind::JumpRelativeConditional(
                         JumpCondition::NonZero,
@@ -113,8 +124,8 @@ impl Instruction {
                     0x3 => Load16Target::StackPointer,
                     _ => panic!("Invalid opcode: {:#x}", byte),
                 };
-                let data: u16 =
-                    (memory.read_byte(a + 1) as u16) | (memory.read_byte(a + 2) as u16) << 8;
+                let data: u16 = (memory.read_byte(operands) as u16)
+                    | (memory.read_byte(operands + 1) as u16) << 8;
                 Instruction::Load16(target, Load16Source::Data(data))
             }
             (0x0..=0x3, 0x2) => {
@@ -152,7 +163,7 @@ impl Instruction {
                 }
             }
             (0x0..=0x3, 0x6) => {
-                let data = memory.read_byte(a + 1);
+                let data = memory.read_byte(operands);
                 let target = match high_bits {
                     0x0 => Load8Operand::Register(Register::B),
                     0x1 => Load8Operand::Register(Register::D),
@@ -173,12 +184,12 @@ impl Instruction {
                 _ => panic!("Invalid opcode: {:#x}", byte),
             },
             (0x0, 0x8) => {
-                let address: u16 =
-                    (memory.read_byte(a + 1) as u16) | (memory.read_byte(a + 2) as u16) << 8;
+                let address: u16 = (memory.read_byte(operands) as u16)
+                    | (memory.read_byte(operands + 1) as u16) << 8;
                 Instruction::Load16(Load16Target::Address(address), Load16Source::StackPointer)
             }
             (0x1..=0x3, 0x8) => {
-                let offset = memory.read_byte(a + 1);
+                let offset = memory.read_byte(operands);
                 match high_bits {
                     0x1 => Instruction::Jump(JumpKind::JumpRelative(offset as i8)),
                     0x2 => Instruction::Jump(JumpKind::JumpRelativeConditional(
@@ -227,7 +238,7 @@ impl Instruction {
                 }
             }
             (0x0..=0x3, 0xE) => {
-                let data = memory.read_byte(a + 1);
+                let data = memory.read_byte(operands);
                 let target = match high_bits {
                     0x0 => Load8Operand::Register(Register::C),
                     0x1 => Load8Operand::Register(Register::E),
@@ -349,7 +360,7 @@ impl Instruction {
                 Instruction::Return(ReturnKind::ReturnConditional(cond))
             }
             (0xE..=0xF, 0x0) => {
-                let a8 = memory.read_byte(a + 1);
+                let a8 = memory.read_byte(operands);
                 match high_bits {
                     0xE => Instruction::Load8(
                         Load8Operand::AtAddress8(a8),
@@ -373,7 +384,8 @@ impl Instruction {
                 Instruction::Pop(reg)
             }
             (0xC..=0xD, 0x2) => {
-                let a16 = (memory.read_byte(a + 1) as u16) | (memory.read_byte(a + 2) as u16) << 8;
+                let a16 = (memory.read_byte(operands) as u16)
+                    | (memory.read_byte(operands + 1) as u16) << 8;
                 let cond = match high_bits {
                     0xC => JumpCondition::NonZero,
                     0xD => JumpCondition::NonCarry,
@@ -387,14 +399,14 @@ impl Instruction {
                 _ => panic!("Invalid opcode: {:#x}", byte),
             },
             (0xC, 0x3) => {
-                let a16: u16 =
-                    (memory.read_byte(a + 1) as u16) | (memory.read_byte(a + 2) as u16) << 8;
+                let a16: u16 = (memory.read_byte(operands) as u16)
+                    | (memory.read_byte(operands + 1) as u16) << 8;
                 Instruction::Jump(JumpKind::Jump(a16))
             }
             (0xF, 0x3) => Instruction::DisableInterrupts,
             (0xF, 0xB) => Instruction::EnableInterrupts,
             (0xC..=0xD, 0x4) => {
-                let a16: u16 = memory.read_2_bytes(a + 1);
+                let a16: u16 = memory.read_2_bytes(operands);
                 let cond = match high_bits {
                     0xC => JumpCondition::NonZero,
                     0xD => JumpCondition::NonCarry,
@@ -413,7 +425,7 @@ impl Instruction {
                 Instruction::Push(reg)
             }
             (0xC..=0xF, 0x6) => {
-                let d8 = memory.read_byte(a + 1);
+                let d8 = memory.read_byte(operands);
                 match high_bits {
                     0xC => Instruction::Add(ArithmeticOperand::Data(d8)),
                     0xD => Instruction::Sub(ArithmeticOperand::Data(d8)),
@@ -438,7 +450,7 @@ impl Instruction {
                 Instruction::Return(ReturnKind::ReturnConditional(cond))
             }
             (0xE, 0x8) => {
-                let s8: i8 = memory.read_byte(a + 1) as i8;
+                let s8: i8 = memory.read_byte(operands) as i8;
                 Instruction::AddPtr(PtrArithOperand::StackPointer, PtrArithOperand::Data(s8))
             }
             (0xC..=0xE, 0x9) => match high_bits {
@@ -448,7 +460,7 @@ impl Instruction {
                 _ => panic!("Invalid opcode: {:#x}", byte),
             },
             (0xF, 0x8) => {
-                let s8: i8 = memory.read_byte(a + 1) as i8;
+                let s8: i8 = memory.read_byte(operands) as i8;
                 Instruction::Load16(
                     Load16Target::Register16(RegisterPair::Hl),
                     Load16Source::SpPlus(s8),
@@ -456,7 +468,7 @@ impl Instruction {
             }
             (0xF, 0x9) => Instruction::Load16(Load16Target::StackPointer, Load16Source::Hl),
             (0xC..=0xD, 0xA) => {
-                let a16: u16 = memory.read_2_bytes(a + 1);
+                let a16: u16 = memory.read_2_bytes(operands);
                 let cond = match high_bits {
                     0xC => JumpCondition::Zero,
                     0xD => JumpCondition::Carry,
@@ -465,7 +477,7 @@ impl Instruction {
                 Instruction::Jump(JumpKind::JumpConditional(cond, a16))
             }
             (0xE..=0xF, 0xA) => {
-                let a16: u16 = memory.read_2_bytes(a + 1);
+                let a16: u16 = memory.read_2_bytes(operands);
                 match high_bits {
                     0xE => Instruction::Load8(
                         Load8Operand::AtAddress16(a16),
@@ -479,7 +491,7 @@ impl Instruction {
                 }
             }
             (0xC..=0xD, 0xC) => {
-                let a16: u16 = memory.read_2_bytes(a + 1);
+                let a16: u16 = memory.read_2_bytes(operands);
                 let cond = match high_bits {
                     0xC => JumpCondition::Zero,
                     0xD => JumpCondition::Carry,
@@ -488,15 +500,15 @@ impl Instruction {
                 Instruction::Call(CallKind::CallConditional(a16, cond))
             }
             (0xC, 0xB) => {
-                let suffix: u8 = memory.read_byte(a + 1);
+                let suffix: u8 = memory.read_byte(operands);
                 Instruction::Instruction16(Instruction::decode_i16_suffix(suffix))
             }
             (0xC, 0xD) => {
-                let a16 = memory.read_2_bytes(a + 1);
+                let a16 = memory.read_2_bytes(operands);
                 Instruction::Call(CallKind::Call(a16))
             }
             (0xC..=0xF, 0xE) => {
-                let d8 = memory.read_byte(a + 1);
+                let d8 = memory.read_byte(operands);
                 match high_bits {
                     0xC => Instruction::AddCarry(ArithmeticOperand::Data(d8)),
                     0xD => Instruction::SubCarry(ArithmeticOperand::Data(d8)),
@@ -676,7 +688,7 @@ impl Instruction {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Load16Target {
     Register16(RegisterPair),
     StackPointer,
@@ -694,7 +706,7 @@ impl fmt::Display for Load16Target {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Load16Source {
     StackPointer,
     Data(u16),
@@ -714,7 +726,7 @@ impl fmt::Display for Load16Source {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Load8Operand {
     Register(Register),
     AtAddress16(u16),
@@ -742,7 +754,7 @@ impl fmt::Display for Load8Operand {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ArithmeticOperand {
     Register(Register),
     AtHl,
@@ -760,7 +772,7 @@ impl fmt::Display for ArithmeticOperand {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum PtrArithOperand {
     Register16(RegisterPair),
     StackPointer,
@@ -778,7 +790,7 @@ impl fmt::Display for PtrArithOperand {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum JumpKind {
     JumpRelative(i8),
     JumpRelativeConditional(JumpCondition, i8),
@@ -802,7 +814,7 @@ impl fmt::Display for JumpKind {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum JumpCondition {
     Zero,
     NonZero,
@@ -822,7 +834,7 @@ impl fmt::Display for JumpCondition {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ReturnKind {
     Return,
     ReturnInterrupt,
@@ -840,7 +852,7 @@ impl fmt::Display for ReturnKind {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Instruction16 {
     RotateLeftCircular(ArithmeticOperand),
     RotateLeft(ArithmeticOperand),
@@ -874,7 +886,7 @@ impl fmt::Display for Instruction16 {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum RotateKind {
     Left,
     LeftCircular,
@@ -894,7 +906,7 @@ impl fmt::Display for RotateKind {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum CallKind {
     Call(u16),
     CallConditional(u16, JumpCondition),
@@ -913,6 +925,7 @@ impl fmt::Display for CallKind {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::memory::test::{blank_memory, dmg_bootrom_memory};
     #[test]
     fn display_nop() {
         assert_eq!(std::format!("{}", Instruction::Nop), "NOP");
@@ -1047,25 +1060,25 @@ mod tests {
     }
     #[test]
     fn decode_nop() {
-        let mut memory = Memory::initialize();
-        memory.rom_bank0[0] = 0;
+        let mut memory = blank_memory();
+        memory.bootrom[0] = 0;
         assert_eq!(Instruction::from_bytes(&memory, 0), Instruction::Nop);
     }
     #[test]
     fn decode_nop_fails() {
-        let memory = Memory::initialize();
+        let memory = blank_memory();
         assert_ne!(Instruction::from_bytes(&memory, 0), Instruction::Stop);
     }
     #[test]
     fn decode_stop() {
-        let mut memory = Memory::initialize();
-        memory.rom_bank0[0] = 0x10;
+        let mut memory = blank_memory();
+        memory.bootrom[0] = 0x10;
         assert_eq!(Instruction::from_bytes(&memory, 0), Instruction::Stop);
     }
     #[test]
     fn decode_ld8() {
-        let mut memory = Memory::initialize();
-        memory.rom_bank0[0] = 0x02;
+        let mut memory = blank_memory();
+        memory.bootrom[0] = 0x02;
         assert_eq!(
             Instruction::from_bytes(&memory, 0),
             Instruction::Load8(
@@ -1076,10 +1089,10 @@ mod tests {
     }
     #[test]
     fn decode_ld16() {
-        let mut memory = Memory::initialize();
-        memory.rom_bank0[0] = 0x01;
-        memory.rom_bank0[1] = 0xCD;
-        memory.rom_bank0[2] = 0xAB;
+        let mut memory = blank_memory();
+        memory.bootrom[0] = 0x01;
+        memory.bootrom[1] = 0xCD;
+        memory.bootrom[2] = 0xAB;
         assert_eq!(
             Instruction::from_bytes(&memory, 0),
             Instruction::Load16(
@@ -1090,8 +1103,8 @@ mod tests {
     }
     #[test]
     fn decode_inc16() {
-        let mut memory = Memory::initialize();
-        memory.rom_bank0[0] = 0x23;
+        let mut memory = blank_memory();
+        memory.bootrom[0] = 0x23;
         assert_eq!(
             Instruction::from_bytes(&memory, 0),
             Instruction::IncrementPtr(PtrArithOperand::Register16(RegisterPair::Hl))
@@ -1099,8 +1112,8 @@ mod tests {
     }
     #[test]
     fn decode_inc() {
-        let mut memory = Memory::initialize();
-        memory.rom_bank0[0] = 0x24;
+        let mut memory = blank_memory();
+        memory.bootrom[0] = 0x24;
         assert_eq!(
             Instruction::from_bytes(&memory, 0),
             Instruction::Increment(ArithmeticOperand::Register(Register::H))
@@ -1108,8 +1121,8 @@ mod tests {
     }
     #[test]
     fn decode_dec() {
-        let mut memory = Memory::initialize();
-        memory.rom_bank0[0] = 0x35;
+        let mut memory = blank_memory();
+        memory.bootrom[0] = 0x35;
         assert_eq!(
             Instruction::from_bytes(&memory, 0),
             Instruction::Decrement(ArithmeticOperand::AtHl)
@@ -1117,7 +1130,7 @@ mod tests {
     }
     #[test]
     pub fn decode_bootrom() {
-        let memory = Memory::initialize();
+        let memory = dmg_bootrom_memory();
         assert_eq!(
             Instruction::from_bytes(&memory, 0),
             Instruction::Load16(Load16Target::StackPointer, Load16Source::Data(0xFFFE))
@@ -9,10 +9,23 @@ pub enum Interrupt {
     Joypad,
 }
 
+// Dispatch takes 2 idle M-cycles, 2 to push PC and 1 to jump
+pub const DISPATCH_CYCLES: u8 = 5;
+
+#[derive(Clone)]
 pub struct InterruptHandler {
     pub ime: bool,
+    // Set by EI, IME only goes high after the next instruction
+    pub enable_scheduled: bool,
 }
 impl InterruptHandler {
+    pub fn new() -> InterruptHandler {
+        InterruptHandler {
+            ime: false,
+            enable_scheduled: false,
+        }
+    }
+
     pub fn interrupts_enabled(&self) -> bool {
         self.ime
     }
@@ -21,8 +34,27 @@ impl InterruptHandler {
         self.ime = true;
     }
 
+    pub fn schedule_enable(&mut self) {
+        self.enable_scheduled = true;
+    }
+
+    // Called before each instruction, so an EI takes effect once the
+    // instruction after it has run
+    pub fn apply_scheduled_enable(&mut self) {
+        if self.enable_scheduled {
+            self.enable_scheduled = false;
+            self.ime = true;
+        }
+    }
+
     pub fn disable_interrupts(&mut self) {
         self.ime = false;
+        self.enable_scheduled = false;
+    }
+
+    // Whether any enabled interrupt is requested, regardless of IME
+    pub fn interrupt_pending(&self, memory: &Memory) -> bool {
+        memory.read_byte(gb::ie) & memory.read_byte(gb::iflags) & 0x1F != 0
     }
 
     pub fn set_interrupt(&self, memory: &mut Memory, interrupt: Interrupt) {
@@ -57,23 +89,18 @@ impl InterruptHandler {
         let ie = memory.read_byte(gb::ie);
         let iflags = memory.read_byte(gb::iflags);
         if ie & 0x1 != 0 && iflags & 0x1 != 0 {
-            println!("VBlank int");
             self.clear_interrupt(memory, Interrupt::VBlank);
             Some(Interrupt::VBlank)
         } else if ie & 0x2 != 0 && iflags & 0x2 != 0 {
-            println!("LcdStat int");
             self.clear_interrupt(memory, Interrupt::LcdStat);
             Some(Interrupt::LcdStat)
         } else if ie & 0x4 != 0 && iflags & 0x4 != 0 {
-            println!("Timer int");
             self.clear_interrupt(memory, Interrupt::Timer);
             Some(Interrupt::Timer)
         } else if ie & 0x8 != 0 && iflags & 0x8 != 0 {
-            println!("Serial int");
             self.clear_interrupt(memory, Interrupt::Serial);
             Some(Interrupt::Serial)
         } else if ie & 0x10 != 0 && iflags & 0x10 != 0 {
-            println!("Joyapd int");
             self.clear_interrupt(memory, Interrupt::Joypad);
             Some(Interrupt::Joypad)
         } else {
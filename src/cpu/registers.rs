@@ -1,5 +1,6 @@
 use std::fmt;
 
+#[derive(Clone)]
 pub struct Registers {
     a: u8,
     b: u8,
@@ -11,7 +12,7 @@ pub struct Registers {
     f: u8,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum Register {
     A,
     B,
@@ -0,0 +1,278 @@
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::battery::BatterySave;
+use crate::cartridge::mbc5::RumbleEvent;
+use crate::cartridge::Cartridge;
+use crate::cpu::Cpu;
+use crate::gb;
+use crate::joypad::Button;
+use crate::memory::{Memory, BOOTROM_END};
+use crate::model::Model;
+use crate::palette::{self, Palette};
+use crate::png;
+use crate::ppu::Ppu;
+
+// Flush dirty battery RAM about once a second so a crash loses little progress
+const FRAMES_PER_SAVE_FLUSH: u32 = 60;
+
+#[derive(Clone)]
+pub struct EmulatorOptions {
+    pub rom_path: PathBuf,
+    // Without a boot ROM the CPU starts at 0x0100 with the post-boot state
+    pub bootrom_path: Option<PathBuf>,
+    pub model: Model,
+    // Defaults to next to the ROM
+    pub save_dir: Option<PathBuf>,
+    pub palette: Palette,
+    // Audio samples per second, or None to skip generating them
+    pub sample_rate: Option<u32>,
+}
+
+impl EmulatorOptions {
+    pub fn new(rom_path: PathBuf) -> EmulatorOptions {
+        EmulatorOptions {
+            rom_path,
+            bootrom_path: None,
+            model: Model::Dmg,
+            save_dir: None,
+            palette: palette::GREEN,
+            sample_rate: None,
+        }
+    }
+}
+
+// Everything needed to resume from a point in time. The cartridge ROM comes
+// along with the rest of the memory map, so a state holds its own copy
+#[derive(Clone)]
+pub struct SaveState {
+    cpu: Cpu,
+    ppu: Ppu,
+    buffer: Vec<u32>,
+    cycles_taken: u32,
+    double_speed_remainder: u32,
+}
+
+pub struct Emulator {
+    pub cpu: Cpu,
+    pub ppu: Ppu,
+    pub buffer: Vec<u32>,
+    cycles_taken: u32,
+    double_speed_remainder: u32,
+    rumble_events: Vec<RumbleEvent>,
+    battery: Option<BatterySave>,
+    frames_since_flush: u32,
+    options: EmulatorOptions,
+}
+
+impl Emulator {
+    pub fn new(options: &EmulatorOptions) -> Result<Emulator, String> {
+        let rom_path = options.rom_path.display();
+        let cartridge = Cartridge::from_file(&options.rom_path)
+            .map_err(|e| format!("could not load ROM {}: {}", rom_path, e))?;
+        for warning in &cartridge.warnings {
+            eprintln!("Warning: {}: {}", rom_path, warning);
+        }
+        eprintln!(
+            "Loaded \"{}\" ({}, {} KiB ROM, {} KiB RAM)",
+            cartridge.header.title,
+            cartridge.header.cartridge_type,
+            cartridge.header.rom_size() / 1024,
+            cartridge.header.ram_size / 1024
+        );
+        let bootrom = match &options.bootrom_path {
+            Some(path) => Emulator::load_bootrom(path)?,
+            None => Vec::new(),
+        };
+
+        let mut cpu = Cpu::new(Memory::initialize(cartridge, bootrom, options.model));
+        let mut ppu = Ppu::new(&cpu.interrupt_handler);
+        ppu.palette = options.palette;
+        cpu.memory
+            .apu
+            .set_sample_rate(options.sample_rate.unwrap_or(0));
+        let battery = Emulator::load_battery(&mut cpu, options);
+        Ok(Emulator {
+            cpu,
+            ppu,
+            buffer: vec![0; gb::total_pixels],
+            cycles_taken: 0,
+            double_speed_remainder: 0,
+            rumble_events: Vec::new(),
+            battery,
+            frames_since_flush: 0,
+            options: options.clone(),
+        })
+    }
+
+    fn load_bootrom(path: &Path) -> Result<Vec<u8>, String> {
+        let mut bootrom = fs::read(path)
+            .map_err(|e| format!("could not load boot ROM {}: {}", path.display(), e))?;
+        let size = BOOTROM_END as usize + 1;
+        if bootrom.len() < size {
+            return Err(format!(
+                "boot ROM {} is {} bytes, expected at least {}",
+                path.display(),
+                bootrom.len(),
+                size
+            ));
+        }
+        bootrom.truncate(size);
+        Ok(bootrom)
+    }
+
+    fn load_battery(cpu: &mut Cpu, options: &EmulatorOptions) -> Option<BatterySave> {
+        let cartridge = &mut cpu.memory.cartridge;
+        if !cartridge.has_battery() {
+            return None;
+        }
+        let battery = BatterySave::for_rom(&options.rom_path, options.save_dir.as_deref());
+        match battery.load(cartridge) {
+            Ok(true) => eprintln!("Loaded save from {}", battery.path.display()),
+            Ok(false) => {}
+            Err(e) => eprintln!("Could not load save {}: {}", battery.path.display(), e),
+        }
+        Some(battery)
+    }
+
+    pub fn flush_save(&mut self) {
+        if let Some(battery) = &self.battery {
+            if let Err(e) = battery.flush(&mut self.cpu.memory.cartridge) {
+                eprintln!("Could not write save {}: {}", battery.path.display(), e);
+            }
+        }
+        self.frames_since_flush = 0;
+    }
+
+    pub fn run_frame(&mut self) {
+        while self.cycles_taken < gb::cycles_per_frame {
+            let mut cycles_instruction = self.cpu.step() as u32;
+            // In double speed the CPU gets two M-cycles for every PPU M-cycle
+            if self.cpu.memory.double_speed() {
+                cycles_instruction += self.double_speed_remainder;
+                self.double_speed_remainder = cycles_instruction % 2;
+                cycles_instruction /= 2;
+            }
+            if !self.cpu.stopped() {
+                self.ppu.step(
+                    cycles_instruction,
+                    &mut self.cpu.memory,
+                    &self.cpu.interrupt_handler,
+                    &mut self.buffer,
+                );
+            }
+            self.cycles_taken += cycles_instruction;
+        }
+        self.cycles_taken %= gb::cycles_per_frame;
+        self.rumble_events
+            .extend(self.cpu.memory.cartridge.take_rumble_events());
+
+        self.frames_since_flush += 1;
+        if self.frames_since_flush >= FRAMES_PER_SAVE_FLUSH && self.cpu.memory.cartridge.ram.dirty {
+            self.flush_save();
+        }
+    }
+
+    // Power cycles the console, keeping the battery save and the link cable
+    pub fn reset(&mut self) -> Result<(), String> {
+        self.flush_save();
+        let mut fresh = Emulator::new(&self.options)?;
+        if let Some(link) = self.cpu.memory.serial.take_link() {
+            fresh.cpu.memory.serial.connect(link);
+        }
+        // The old machine flushes its (already saved) RAM as it's dropped
+        mem::swap(self, &mut fresh);
+        Ok(())
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            cpu: self.cpu.clone(),
+            ppu: self.ppu.clone(),
+            buffer: self.buffer.clone(),
+            cycles_taken: self.cycles_taken,
+            double_speed_remainder: self.double_speed_remainder,
+        }
+    }
+
+    pub fn load_state(&mut self, state: &SaveState) {
+        let link = self.cpu.memory.serial.take_link();
+        let palette = self.ppu.palette;
+        self.cpu = state.cpu.clone();
+        self.ppu = state.ppu.clone();
+        self.buffer = state.buffer.clone();
+        self.cycles_taken = state.cycles_taken;
+        self.double_speed_remainder = state.double_speed_remainder;
+        if let Some(link) = link {
+            self.cpu.memory.serial.connect(link);
+        }
+        // The colour scheme is a frontend choice, not part of the machine
+        self.ppu.palette = palette;
+    }
+
+    // Also recolours the frame on screen, so the change shows while paused
+    pub fn set_palette(&mut self, palette: Palette) {
+        let old = self.ppu.palette;
+        for pixel in self.buffer.iter_mut() {
+            if let Some(shade) = old.iter().position(|color| color == pixel) {
+                *pixel = palette[shade];
+            }
+        }
+        self.ppu.palette = palette;
+        // Kept for reset, which builds a new emulator from the options
+        self.options.palette = palette;
+    }
+
+    // Writes the current frame next to the battery saves as <rom>-N.png
+    pub fn save_screenshot(&self) -> Result<PathBuf, String> {
+        let rom_path = &self.options.rom_path;
+        let dir = match &self.options.save_dir {
+            Some(dir) => dir.as_path(),
+            None => rom_path.parent().unwrap_or_else(|| Path::new("")),
+        };
+        let stem = rom_path.file_stem().unwrap_or_default().to_string_lossy();
+        let path = (1..)
+            .map(|n| dir.join(format!("{}-{}.png", stem, n)))
+            .find(|path| !path.exists())
+            .unwrap();
+        png::write_rgb(&path, gb::screen_width, gb::screen_height, &self.buffer)
+            .map_err(|e| format!("could not write screenshot {}: {}", path.display(), e))?;
+        Ok(path)
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.cpu.memory.joypad.set_button(button, pressed);
+    }
+
+    // Bytes the game sent over the serial port since the last call, once
+    // capture_serial_output has turned recording on
+    pub fn serial_output(&mut self) -> Vec<u8> {
+        self.cpu.memory.serial.take_sent()
+    }
+
+    pub fn capture_serial_output(&mut self) {
+        self.cpu.memory.serial.capture_output(true);
+    }
+
+    // Interleaved stereo samples generated since the last call
+    pub fn audio_samples(&mut self) -> Vec<i16> {
+        self.cpu.memory.apu.take_samples()
+    }
+
+    pub fn adjust_audio_rate(&mut self, ratio: f32) {
+        self.cpu.memory.apu.set_rate_adjustment(ratio);
+    }
+
+    // Rumble motor changes since the last call, in the order the game made them
+    pub fn rumble_events(&mut self) -> Vec<RumbleEvent> {
+        self.rumble_events.drain(..).collect()
+    }
+}
+
+// Also runs while unwinding from a panic, so progress survives emulator crashes
+impl Drop for Emulator {
+    fn drop(&mut self) {
+        self.flush_save();
+    }
+}
@@ -5,8 +5,11 @@ pub use self::init_state::INIT_PC as init_pc_value;
 pub use self::init_state::INIT_SP as init_sp_value;
 pub use self::interrupt_pointers::IE as ie;
 pub use self::interrupt_pointers::IF as iflags;
+pub use self::mmio_pointers::BG_PALETTE as bgp_addr;
+pub use self::mmio_pointers::DIV as div_addr;
 pub use self::mmio_pointers::DMA_TRANSFER as dma_reg;
 pub use self::mmio_pointers::JOYPAD as joypad;
+pub use self::mmio_pointers::KEY1 as key1_addr;
 pub use self::mmio_pointers::LCDC as lcdc_addr;
 pub use self::mmio_pointers::LCD_STATUS as lcd_stat;
 pub use self::mmio_pointers::LY as ly_addr;
@@ -44,6 +47,8 @@ pub mod mmio_pointers {
     pub const WY: u16 = 0xFF4A;
     pub const WX: u16 = 0xFF4B;
     pub const JOYPAD: u16 = 0xFF00;
+    pub const DIV: u16 = 0xFF04;
+    pub const KEY1: u16 = 0xFF4D;
 }
 
 pub mod interrupt_pointers {
@@ -52,5 +57,6 @@ pub mod interrupt_pointers {
 }
 
 pub mod timings {
-    pub const CYCLES_PER_FRAME: u32 = 69905;
+    // M-cycles: 154 lines of 114, or 70224 T-cycles at 4 MiHz (~59.73 Hz)
+    pub const CYCLES_PER_FRAME: u32 = 17556;
 }
@@ -0,0 +1,127 @@
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+// P1 at 0xFF00. Bits 4 (P14, d-pad) and 5 (P15, buttons) select which row
+// drives the active-low input lines in bits 0-3
+#[derive(Clone)]
+pub struct Joypad {
+    // Bit 0-3 d-pad right, left, up, down, bit 4-7 A, B, select, start
+    pressed: u8,
+    select: u8,
+    interrupt: bool,
+}
+
+impl Button {
+    fn mask(&self) -> u8 {
+        match self {
+            Button::Right => 0x01,
+            Button::Left => 0x02,
+            Button::Up => 0x04,
+            Button::Down => 0x08,
+            Button::A => 0x10,
+            Button::B => 0x20,
+            Button::Select => 0x40,
+            Button::Start => 0x80,
+        }
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad {
+            pressed: 0,
+            select: 0,
+            interrupt: false,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        0xC0 | self.select | self.lines()
+    }
+
+    // Only the select bits are writable
+    pub fn write(&mut self, value: u8) {
+        let lines = self.lines();
+        self.select = value & 0x30;
+        self.check_falling_edge(lines);
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        let lines = self.lines();
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+        self.check_falling_edge(lines);
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        let interrupt = self.interrupt;
+        self.interrupt = false;
+        interrupt
+    }
+
+    fn lines(&self) -> u8 {
+        let mut low = 0;
+        if self.select & 0x10 == 0 {
+            low |= self.pressed & 0x0F;
+        }
+        if self.select & 0x20 == 0 {
+            low |= self.pressed >> 4;
+        }
+        !low & 0x0F
+    }
+
+    // The interrupt fires when any input line goes from high to low
+    fn check_falling_edge(&mut self, old_lines: u8) {
+        if old_lines & !self.lines() != 0 {
+            self.interrupt = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rows_are_selected_by_p14_and_p15() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(Button::Down, true);
+        joypad.set_button(Button::Start, true);
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xE7);
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD7);
+        joypad.write(0x30);
+        assert_eq!(joypad.read(), 0xFF);
+        // The input lines can't be written
+        joypad.write(0x00);
+        assert_eq!(joypad.read() & 0x0F, 0x07);
+    }
+
+    #[test]
+    fn interrupt_only_on_high_to_low() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x10);
+        joypad.set_button(Button::Up, true);
+        assert!(!joypad.take_interrupt());
+        joypad.set_button(Button::A, true);
+        assert!(joypad.take_interrupt());
+        joypad.set_button(Button::A, false);
+        assert!(!joypad.take_interrupt());
+        // Selecting the d-pad row with Up held pulls a line low
+        joypad.write(0x00);
+        assert!(joypad.take_interrupt());
+    }
+}
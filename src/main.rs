@@ -1,68 +1,560 @@
+extern crate clap;
 extern crate minifb;
-use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
-use std::time::Instant;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use minifb::{KeyRepeat, Scale, ScaleMode, Window, WindowOptions};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
 
+mod apu;
+mod audio;
+mod cartridge;
+mod config;
 mod cpu;
+mod emulator;
 mod gb;
+mod joypad;
 mod memory;
+mod model;
+mod pacing;
+mod palette;
+mod png;
 mod ppu;
+mod serial;
 mod timer;
 
-use crate::cpu::interrupt_handler::Interrupt;
-use crate::cpu::Cpu;
-use crate::ppu::Ppu;
+use crate::audio::player::PlayerSink;
+use crate::audio::wav::WavSink;
+use crate::audio::{AudioSink, NullSink};
+use crate::cartridge::header::{CartridgeHeader, CgbSupport};
+use crate::cartridge::mbc5::RumbleEvent;
+use crate::cartridge::Cartridge;
+use crate::config::{Action, Binding, Config};
+use crate::cpu::instruction::{Instruction, ILLEGAL_OPCODES};
+use crate::emulator::{Emulator, EmulatorOptions, SaveState};
+use crate::memory::Memory;
+use crate::model::Model;
+use crate::pacing::{Pacer, Pacing};
+use crate::serial::link::StreamTransport;
+use crate::serial::printer::Printer;
+
+// Frames run per window update while fast-forward is held
+const FAST_FORWARD_FRAMES: u32 = 4;
+const STATE_SLOTS: usize = 10;
 
 fn main() {
+    let rom_arg = Arg::with_name("ROM")
+        .help("Cartridge ROM image")
+        .required(true);
+    let emulator_args = [
+        rom_arg.clone(),
+        Arg::with_name("bootrom")
+            .long("bootrom")
+            .value_name("FILE")
+            .help("Boot ROM to run first, otherwise start at 0x0100 in the post-boot state"),
+        Arg::with_name("model")
+            .long("model")
+            .value_name("MODEL")
+            .default_value("dmg")
+            .help("Hardware model: dmg0, dmg, mgb, sgb or cgb"),
+        Arg::with_name("palette")
+            .long("palette")
+            .value_name("NAME")
+            .help(
+                "DMG colours: green, pocket, light, high_contrast, colorblind, a palette \
+                 from the config file or four hex colours like \"#e0f8d0,#88c070,#346856,#081820\"",
+            ),
+        Arg::with_name("save-dir")
+            .long("save-dir")
+            .value_name("DIR")
+            .help("Directory for battery saves, defaults to next to the ROM"),
+        Arg::with_name("link-listen")
+            .long("link-listen")
+            .value_name("ADDRESS")
+            .conflicts_with("link-connect")
+            .help("Wait for a link cable peer on host:port or a Unix socket path"),
+        Arg::with_name("link-connect")
+            .long("link-connect")
+            .value_name("ADDRESS")
+            .help("Connect the link cable to a peer on host:port or a Unix socket path"),
+        Arg::with_name("printer")
+            .long("printer")
+            .value_name("DIR")
+            .conflicts_with_all(&["link-listen", "link-connect"])
+            .help("Plug in a Game Boy Printer that saves printouts as PNGs in DIR"),
+        Arg::with_name("sample-rate")
+            .long("sample-rate")
+            .value_name("HZ")
+            .default_value("48000")
+            .help("Audio sample rate"),
+    ];
+    let matches = App::new("gbemu")
+        .about("Game Boy emulator")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Run a ROM in a window")
+                .args(&emulator_args)
+                .arg(
+                    Arg::with_name("scale")
+                        .long("scale")
+                        .value_name("N")
+                        .default_value("4")
+                        .possible_values(&["1", "2", "4", "8", "16", "32"])
+                        .help("Window scale factor"),
+                )
+                .arg(
+                    Arg::with_name("speed")
+                        .long("speed")
+                        .value_name("FACTOR")
+                        .default_value("1.0")
+                        .help("Emulation speed relative to hardware, 0 for unlimited"),
+                )
+                .arg(
+                    Arg::with_name("pacing")
+                        .long("pacing")
+                        .value_name("CLOCK")
+                        .default_value("audio")
+                        .possible_values(&["audio", "clock"])
+                        .help("Keep time by the sound card, or by the wall clock when silent"),
+                )
+                .arg(
+                    Arg::with_name("config")
+                        .long("config")
+                        .value_name("FILE")
+                        .help("Key bindings file, defaults to ~/.config/gbemu/config if present"),
+                )
+                .arg(
+                    Arg::with_name("audio")
+                        .long("audio")
+                        .value_name("OUTPUT")
+                        .default_value("play")
+                        .possible_values(&["play", "wav", "none"])
+                        .help("Play sound, record it to the --wav file or turn it off"),
+                )
+                .arg(
+                    Arg::with_name("wav")
+                        .long("wav")
+                        .value_name("FILE")
+                        .required_if("audio", "wav")
+                        .help("WAV file to record sound to with --audio wav"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Print the cartridge header")
+                .arg(rom_arg.clone()),
+        )
+        .subcommand(
+            SubCommand::with_name("disasm")
+                .about("Disassemble the first two ROM banks")
+                .arg(rom_arg)
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .value_name("ADDRESS")
+                        .default_value("0000")
+                        .help("First address, in hex"),
+                )
+                .arg(
+                    Arg::with_name("end")
+                        .long("end")
+                        .value_name("ADDRESS")
+                        .default_value("7FFF")
+                        .help("Last address, in hex"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("headless")
+                .about("Run a ROM without a window")
+                .args(&emulator_args)
+                .arg(
+                    Arg::with_name("frames")
+                        .long("frames")
+                        .value_name("N")
+                        .default_value("600")
+                        .help("Number of frames to run"),
+                )
+                .arg(
+                    Arg::with_name("wav")
+                        .long("wav")
+                        .value_name("FILE")
+                        .help("Record the sound to a 16 bit stereo WAV file"),
+                )
+                .arg(
+                    Arg::with_name("wav-seconds")
+                        .long("wav-seconds")
+                        .value_name("N")
+                        .requires("wav")
+                        .help("Stop after recording N seconds of sound, instead of --frames"),
+                ),
+        )
+        .get_matches();
+
+    let result = match matches.subcommand() {
+        ("run", Some(args)) => run(args),
+        ("info", Some(args)) => info(args),
+        ("disasm", Some(args)) => disasm(args),
+        ("headless", Some(args)) => headless(args),
+        _ => unreachable!(),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn emulator_options(args: &ArgMatches, config: &Config) -> Result<EmulatorOptions, String> {
+    let mut options = EmulatorOptions::new(PathBuf::from(args.value_of("ROM").unwrap()));
+    options.bootrom_path = args.value_of("bootrom").map(PathBuf::from);
+    options.model = args.value_of("model").unwrap().parse()?;
+    if let Some(name) = args.value_of("palette").or(config.palette.as_deref()) {
+        options.palette = config.find_palette(name)?;
+    }
+    options.save_dir = args.value_of("save-dir").map(PathBuf::from);
+    Ok(options)
+}
+
+fn load_config(args: &ArgMatches) -> Result<Config, String> {
+    if let Some(path) = args.value_of("config") {
+        return Config::load(Path::new(path));
+    }
+    match Config::default_path() {
+        Some(path) if path.exists() => Config::load(&path),
+        _ => Ok(Config::new()),
+    }
+}
+
+fn connect_link(args: &ArgMatches, emulator: &mut Emulator) -> Result<(), String> {
+    let transport = if let Some(address) = args.value_of("link-listen") {
+        eprintln!("Waiting for link cable peer on {}", address);
+        StreamTransport::listen(address)
+    } else if let Some(address) = args.value_of("link-connect") {
+        StreamTransport::connect(address)
+    } else if let Some(dir) = args.value_of("printer") {
+        let printer = Printer::new(PathBuf::from(dir));
+        emulator.cpu.memory.serial.connect(Box::new(printer));
+        return Ok(());
+    } else {
+        return Ok(());
+    };
+    let transport = transport.map_err(|e| format!("could not open link cable: {}", e))?;
+    emulator.cpu.memory.serial.connect(Box::new(transport));
+    Ok(())
+}
+
+fn parse_number<T: std::str::FromStr>(args: &ArgMatches, name: &str) -> Result<T, String> {
+    let value = args.value_of(name).unwrap();
+    value
+        .parse()
+        .map_err(|_| format!("invalid value \"{}\" for --{}", value, name))
+}
+
+fn parse_sample_rate(args: &ArgMatches) -> Result<u32, String> {
+    match parse_number(args, "sample-rate")? {
+        0 => Err(String::from("--sample-rate must be more than 0")),
+        rate => Ok(rate),
+    }
+}
+
+fn parse_address(args: &ArgMatches, name: &str) -> Result<u16, String> {
+    let value = args.value_of(name).unwrap();
+    u16::from_str_radix(value.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("invalid address \"{}\" for --{}", value, name))
+}
+
+fn run(args: &ArgMatches) -> Result<(), String> {
+    let config = load_config(args)?;
+    let mut options = emulator_options(args, &config)?;
+    let scale = match args.value_of("scale").unwrap() {
+        "1" => Scale::X1,
+        "2" => Scale::X2,
+        "8" => Scale::X8,
+        "16" => Scale::X16,
+        "32" => Scale::X32,
+        _ => Scale::X4,
+    };
+    let speed: f64 = parse_number(args, "speed")?;
+    if !speed.is_finite() || speed < 0.0 {
+        return Err(String::from("--speed must be a number of at least 0"));
+    }
+
+    let sample_rate = parse_sample_rate(args)?;
+    let mut sink: Box<dyn AudioSink> = match args.value_of("audio").unwrap() {
+        "play" => match PlayerSink::spawn(sample_rate) {
+            Ok(player) => {
+                eprintln!("Playing sound through {}", player.player);
+                Box::new(player)
+            }
+            Err(e) => {
+                eprintln!("Could not play sound: {}", e);
+                Box::new(NullSink)
+            }
+        },
+        "wav" => Box::new(create_wav(args, sample_rate)?),
+        _ => Box::new(NullSink),
+    };
+    if args.value_of("audio").unwrap() != "none" {
+        options.sample_rate = Some(sample_rate);
+    }
+
+    let mut emulator = Emulator::new(&options)?;
+    connect_link(args, &mut emulator)?;
     let mut window = Window::new(
-        "Test - ESC to exit",
+        "gbemu",
         gb::screen_width,
         gb::screen_height,
         WindowOptions {
             borderless: false,
             title: true,
             resize: true,
-            scale: Scale::X4,
+            scale,
             scale_mode: ScaleMode::UpperLeft,
             topmost: true,
             transparency: false,
             none: false,
         },
     )
-    .unwrap_or_else(|e| {
-        panic!("{}", e);
-    });
-
-    // Limit to max ~60 fps update rate
-    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
-    let mut buffer: Vec<u32> = vec![0; gb::total_pixels];
-
-    let mut cpu = Cpu::new();
-    let mut ppu = Ppu::new(&cpu.interrupt_handler);
-    let mut cycles_taken = 0;
-    #[allow(clippy::never_loop)]
-    while window.is_open() && !window.is_key_down(Key::Escape) {
-        if window.is_key_down(Key::Enter) {
-            cpu.interrupt_handler
-                .set_interrupt(&mut cpu.memory, Interrupt::Joypad);
-            cpu.memory.write_byte(gb::joypad, 0x28);
-            println!("Pressed start");
+    .map_err(|e| format!("could not open window: {}", e))?;
+
+    // The pacer is the only thing keeping time, so minifb mustn't throttle too
+    window.limit_update_rate(None);
+    let pacing = match args.value_of("pacing").unwrap() {
+        "clock" => Pacing::WallClock,
+        _ => Pacing::Audio,
+    };
+    let mut pacer = Pacer::new(pacing, speed, sample_rate);
+
+    let mut paused = false;
+    let mut rumbling = false;
+    let mut title = String::from("gbemu");
+    let mut slot = 0;
+    let mut states: Vec<Option<SaveState>> = (0..STATE_SLOTS).map(|_| None).collect();
+    let palettes = config.palettes();
+    let mut palette_index = palettes.iter().position(|(_, p)| *p == options.palette);
+    while window.is_open() {
+        for button in Config::buttons() {
+            let down = config
+                .keys_for(Binding::Button(button))
+                .any(|key| window.is_key_down(key));
+            emulator.set_button(button, down);
+        }
+        let mut frames = if paused { 0 } else { 1 };
+        let mut quit = false;
+        for (key, binding) in config.bindings.iter() {
+            let action = match binding {
+                Binding::Action(action) => *action,
+                Binding::Button(_) => continue,
+            };
+            if action == Action::FastForward {
+                if !paused && window.is_key_down(*key) {
+                    frames = FAST_FORWARD_FRAMES;
+                }
+                continue;
+            }
+            if !window.is_key_pressed(*key, KeyRepeat::No) {
+                continue;
+            }
+            match action {
+                Action::Quit => quit = true,
+                Action::Pause => {
+                    paused = !paused;
+                    frames = 0;
+                }
+                Action::FastForward => {}
+                Action::FrameAdvance => {
+                    paused = true;
+                    frames = 1;
+                }
+                Action::Reset => emulator.reset()?,
+                Action::Screenshot => match emulator.save_screenshot() {
+                    Ok(path) => eprintln!("Saved screenshot {}", path.display()),
+                    Err(e) => eprintln!("{}", e),
+                },
+                Action::SaveState => {
+                    states[slot] = Some(emulator.save_state());
+                    eprintln!("Saved state to slot {}", slot);
+                }
+                Action::LoadState => match &states[slot] {
+                    Some(state) => {
+                        emulator.load_state(state);
+                        eprintln!("Loaded state from slot {}", slot);
+                    }
+                    None => eprintln!("Slot {} is empty", slot),
+                },
+                Action::NextSlot => {
+                    slot = (slot + 1) % STATE_SLOTS;
+                    eprintln!("Selected state slot {}", slot);
+                }
+                Action::NextPalette => {
+                    // A palette given as hex colours isn't in the list, so
+                    // the first press goes to the first preset
+                    let next = palette_index.map_or(0, |i| (i + 1) % palettes.len());
+                    palette_index = Some(next);
+                    emulator.set_palette(palettes[next].1);
+                    eprintln!("Palette {}", palettes[next].0);
+                }
+            }
+        }
+        if quit {
+            break;
+        }
+
+        for _ in 0..frames {
+            emulator.run_frame();
+        }
+        sink.write(&emulator.audio_samples())
+            .map_err(|e| format!("could not write sound: {}", e))?;
+        // minifb has no way to vibrate, so show the motor state in the title
+        if let Some(event) = emulator.rumble_events().last() {
+            rumbling = *event == RumbleEvent::Start;
         }
-        let start_time = Instant::now();
-        while cycles_taken < gb::cycles_per_frame {
-            let cycles_instruction = cpu.step() as u32;
-            ppu.step(
-                cycles_instruction,
-                &mut cpu.memory,
-                &cpu.interrupt_handler,
-                &mut buffer,
-            );
-            cycles_taken += cycles_instruction;
+        let new_title = format!(
+            "gbemu{}{}",
+            if paused { " [paused]" } else { "" },
+            if rumbling { " [rumble]" } else { "" }
+        );
+        if new_title != title {
+            window.set_title(&new_title);
+            title = new_title;
         }
-        cycles_taken %= gb::cycles_per_frame;
         window
-            .update_with_buffer(&buffer, gb::screen_width, gb::screen_height)
-            .unwrap();
+            .update_with_buffer(&emulator.buffer, gb::screen_width, gb::screen_height)
+            .map_err(|e| format!("could not draw frame: {}", e))?;
+
+        let ratio = pacer.end_frame(sink.as_ref(), !paused && frames == 1);
+        emulator.adjust_audio_rate(ratio);
+    }
+    println!("{}", pacer.stats);
+    sink.finish()
+        .map_err(|e| format!("could not write sound: {}", e))
+}
+
+fn info(args: &ArgMatches) -> Result<(), String> {
+    let rom_path = args.value_of("ROM").unwrap();
+    let rom = fs::read(rom_path).map_err(|e| format!("could not read ROM {}: {}", rom_path, e))?;
+    // Only the header, so this works for mappers the emulator can't run
+    let header = CartridgeHeader::parse(&rom)
+        .map_err(|e| format!("could not load ROM {}: {}", rom_path, e))?;
+    println!("Title:           {}", header.title);
+    println!("Cartridge type:  {}", header.cartridge_type);
+    println!(
+        "ROM size:        {} KiB ({} banks)",
+        header.rom_size() / 1024,
+        header.rom_banks
+    );
+    println!("RAM size:        {} KiB", header.ram_size / 1024);
+    println!(
+        "CGB support:     {}",
+        match header.cgb {
+            CgbSupport::None => "none",
+            CgbSupport::Compatible => "compatible",
+            CgbSupport::Only => "CGB only",
+        }
+    );
+    println!("SGB support:     {}", if header.sgb { "yes" } else { "no" });
+    println!(
+        "Destination:     {}",
+        if header.japanese { "Japan" } else { "overseas" }
+    );
+    println!("Licensee:        {}", header.licensee);
+    println!("Version:         {}", header.version);
+    println!("Header checksum: {:#04x}", header.header_checksum);
+    println!("Global checksum: {:#06x}", header.global_checksum);
+    for warning in header.validate(&rom) {
+        println!("Warning: {}", warning);
+    }
+    Ok(())
+}
+
+fn disasm(args: &ArgMatches) -> Result<(), String> {
+    let rom_path = args.value_of("ROM").unwrap();
+    let start = parse_address(args, "start")?;
+    let end = parse_address(args, "end")?;
+    if start > end || end > memory::ROM1_END {
+        return Err(format!(
+            "address range {:#06x}-{:#06x} is outside 0x0000-{:#06x}",
+            start,
+            end,
+            memory::ROM1_END
+        ));
+    }
+    let cartridge = Cartridge::from_file(rom_path)
+        .map_err(|e| format!("could not load ROM {}: {}", rom_path, e))?;
+    let memory = Memory::initialize(cartridge, Vec::new(), Model::Dmg);
 
-        timer::sleep_to_frame_end(start_time);
+    let mut address = start as u32;
+    while address <= end as u32 {
+        let opcode = memory.read_byte(address as u16);
+        // Lay out illegal opcodes as data so the rest of the ROM still decodes
+        let (size, text) = if ILLEGAL_OPCODES.contains(&opcode) {
+            (1, format!("DB ${:02x}", opcode))
+        } else {
+            let instruction = Instruction::from_bytes(&memory, address as u16);
+            let (size, _cycles) = Instruction::size_and_cycles(&instruction);
+            (size as u32, format!("{}", instruction))
+        };
+        let bytes: Vec<String> = (0..size)
+            .map(|i| format!("{:02x}", memory.read_byte((address + i) as u16)))
+            .collect();
+        println!("{:04x}: {:<9} {}", address, bytes.join(" "), text);
+        address += size;
     }
+    Ok(())
+}
+
+fn headless(args: &ArgMatches) -> Result<(), String> {
+    let mut options = emulator_options(args, &Config::new())?;
+    let frames: u32 = parse_number(args, "frames")?;
+    let sample_rate = parse_sample_rate(args)?;
+    let mut wav = match args.value_of("wav") {
+        Some(_) => {
+            options.sample_rate = Some(sample_rate);
+            Some(create_wav(args, sample_rate)?)
+        }
+        None => None,
+    };
+    // Interleaved, so two samples for each second of each stereo frame
+    let mut samples_left = match args.value_of("wav-seconds") {
+        Some(_) => Some(parse_number::<usize>(args, "wav-seconds")? * sample_rate as usize * 2),
+        None => None,
+    };
+
+    let mut emulator = Emulator::new(&options)?;
+    connect_link(args, &mut emulator)?;
+    emulator.capture_serial_output();
+    let mut frame = 0;
+    while match samples_left {
+        Some(left) => left > 0,
+        None => frame < frames,
+    } {
+        emulator.run_frame();
+        frame += 1;
+        // Test ROMs report results as text over the serial port. It's all
+        // that goes to stdout, so status messages are written to stderr
+        let output = emulator.serial_output();
+        if !output.is_empty() {
+            print!("{}", String::from_utf8_lossy(&output));
+            io::stdout().flush().map_err(|e| e.to_string())?;
+        }
+        if let Some(wav) = &mut wav {
+            let samples = emulator.audio_samples();
+            let count = samples_left.map_or(samples.len(), |left| left.min(samples.len()));
+            wav.write(&samples[..count])
+                .map_err(|e| format!("could not write sound: {}", e))?;
+            samples_left = samples_left.map(|left| left - count);
+        }
+    }
+    if let Some(wav) = &mut wav {
+        wav.finish()
+            .map_err(|e| format!("could not write sound: {}", e))?;
+    }
+    Ok(())
+}
+
+fn create_wav(args: &ArgMatches, sample_rate: u32) -> Result<WavSink, String> {
+    let path = args.value_of("wav").unwrap();
+    WavSink::create(Path::new(path), sample_rate)
+        .map_err(|e| format!("could not create WAV file {}: {}", path, e))
 }
@@ -1,22 +1,31 @@
-use std::env;
-use std::fs::File;
-use std::io::prelude::*;
-use std::io::Read;
-use std::io::SeekFrom;
+pub mod dma;
 
+use crate::apu::{self, Apu};
+use crate::cartridge::Cartridge;
 use crate::gb;
+use crate::joypad::Joypad;
+use crate::memory::dma::OamDma;
+use crate::model::Model;
+use crate::serial::{self, Serial};
+use crate::timer::{self, Timer};
 
+#[derive(Clone)]
 pub struct Memory {
-    pub rom_bank0: Vec<u8>,
-    pub rom_bank1: Vec<u8>,
+    pub bootrom: Vec<u8>,
+    pub bootrom_mapped: bool,
     pub vram: Vec<u8>,
-    pub eram: Vec<u8>,
     pub wram: Vec<u8>,
     pub oam: Vec<u8>,
     pub io: Vec<u8>,
     pub hram: Vec<u8>,
     pub interrupt_register: u8,
-    pub rom_low_bytes: Vec<u8>,
+    pub cartridge: Cartridge,
+    pub dma: OamDma,
+    pub timer: Timer,
+    pub apu: Apu,
+    pub serial: Serial,
+    pub joypad: Joypad,
+    pub model: Model,
 }
 
 pub const ROM0_START: u16 = 0x0000;
@@ -29,95 +38,149 @@ pub const ERAM_START: u16 = 0xA000;
 pub const ERAM_END: u16 = 0xBFFF;
 pub const WRAM_START: u16 = 0xC000;
 pub const WRAM_END: u16 = 0xDFFF;
+pub const ECHO_START: u16 = 0xE000;
+pub const ECHO_END: u16 = 0xFDFF;
 pub const OAM_START: u16 = 0xFE00;
 pub const OAM_END: u16 = 0xFE9F;
+pub const UNUSABLE_START: u16 = 0xFEA0;
+pub const UNUSABLE_END: u16 = 0xFEFF;
 pub const IO_START: u16 = 0xFF00;
 pub const IO_END: u16 = 0xFF7F;
 pub const HRAM_START: u16 = 0xFF80;
 pub const HRAM_END: u16 = 0xFFFE;
 pub const IR: u16 = 0xFFFF;
+pub const BOOTROM_END: u16 = 0x00FF;
 
 impl Memory {
-    pub fn initialize() -> Memory {
-        let bootrom_path = env::var("BOOTROM").unwrap();
-        let rom_path = env::var("ROM").unwrap();
-        let mut rom_bank0 = vec![0; (ROM0_END - ROM0_START + 1) as usize];
-        let mut rom_low_bytes = vec![0; 0x100];
-        File::open(bootrom_path)
-            .unwrap()
-            .read_exact(&mut rom_bank0.as_mut_slice()[0..=255])
-            .unwrap();
-        let mut rom = File::open(rom_path).unwrap();
-        rom.read_exact(&mut rom_low_bytes).unwrap();
-        rom.read_exact(&mut rom_bank0.as_mut_slice()[0x100..=(ROM0_END as usize)])
-            .unwrap();
-        let mut rom_bank1 = vec![0; (ROM1_END - ROM1_START + 1) as usize];
-        rom.read_exact(&mut rom_bank1).unwrap();
+    // An empty boot ROM starts the CPU at 0x0100 with the post-boot state
+    pub fn initialize(cartridge: Cartridge, bootrom: Vec<u8>, model: Model) -> Memory {
         let vram = vec![0; (VRAM_END - VRAM_START + 1) as usize];
-        let eram = vec![0; (ERAM_END - ERAM_START + 1) as usize];
         let wram = vec![0; (WRAM_END - WRAM_START + 1) as usize];
         let oam = vec![0; (OAM_END - OAM_START + 1) as usize];
         let mut io = vec![0; (IO_END - IO_START + 1) as usize];
         io[0] = 0xCF;
+        // Only the CGB has a speed switch, bit 7 is the current speed
+        io[(gb::key1_addr - IO_START) as usize] = if model == Model::Cgb { 0x7E } else { 0xFF };
         let hram = vec![0; (HRAM_END - HRAM_START + 1) as usize];
         let interrupt_register = 0;
 
         Memory {
-            rom_bank0,
-            rom_bank1,
+            bootrom_mapped: !bootrom.is_empty(),
+            bootrom,
             vram,
-            eram,
             wram,
             oam,
             io,
             hram,
             interrupt_register,
-            rom_low_bytes,
+            cartridge,
+            dma: OamDma::new(),
+            timer: Timer::new(),
+            apu: Apu::new(model == Model::Cgb),
+            serial: Serial::new(model == Model::Cgb),
+            joypad: Joypad::new(),
+            model,
         }
     }
 
+    // Advance everything on the bus that runs alongside the CPU
+    pub fn tick(&mut self, cycles: u32) {
+        self.cartridge.tick(cycles);
+        self.timer.tick(cycles);
+        self.apu.tick(cycles, self.double_speed());
+        for _ in 0..self.timer.take_apu_steps() {
+            self.apu.step_frame_sequencer();
+        }
+        self.serial.tick(cycles);
+        for _ in 0..cycles {
+            if let Some((source, offset)) = self.dma.step() {
+                self.oam[offset] = self.read_mapped(source);
+            }
+        }
+    }
+
+    // During OAM DMA the CPU can only reach HRAM and the IO registers, which
+    // aren't on the bus the transfer is using
     pub fn read_byte(&self, address: u16) -> u8 {
+        if self.dma.active() && address < IO_START {
+            return 0xFF;
+        }
+        self.read_mapped(address)
+    }
+
+    // The PPU has its own path to VRAM that OAM DMA doesn't block
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[(address - VRAM_START) as usize]
+    }
+
+    fn read_mapped(&self, address: u16) -> u8 {
         // if address == 0xFF80 {
         //     println!("Reading from ff80h which has val {:#0x}", self.hram[0]);
         // };
         match address {
-            ROM0_START..=ROM0_END => self.rom_bank0[address as usize],
-            ROM1_START..=ROM1_END => self.rom_bank1[(address as usize) - 0x4000],
+            ROM0_START..=BOOTROM_END if self.bootrom_mapped => self.bootrom[address as usize],
+            ROM0_START..=ROM1_END => self.cartridge.read_rom(address),
             VRAM_START..=VRAM_END => self.vram[(address as usize) - 0x8000],
-            ERAM_START..=ERAM_END => self.eram[(address as usize) - 0xA000],
+            ERAM_START..=ERAM_END => self.cartridge.read_ram(address),
             WRAM_START..=WRAM_END => self.wram[(address as usize) - 0xC000],
-            0xE000..=0xFDFF => 0xFF,
+            // Echo RAM is WRAM seen through a 13 bit address bus
+            ECHO_START..=ECHO_END => self.wram[(address - ECHO_START) as usize],
             OAM_START..=OAM_END => self.oam[(address as usize) - 0xFE00],
-            0xFEA0..=0xFEFF => panic!(
-                "Address {:#0x} attempts to access prohibited region of memory",
-                address
-            ),
+            UNUSABLE_START..=UNUSABLE_END => self.read_unusable(address),
+            gb::joypad => self.joypad.read(),
+            serial::SB..=serial::SC => self.serial.read(address),
+            timer::DIV..=timer::TAC => self.timer.read(address),
+            apu::NR10..=apu::WAVE_RAM_END => self.apu.read(address),
             IO_START..=IO_END => self.io[(address as usize) - 0xFF00],
             HRAM_START..=HRAM_END => self.hram[(address as usize) - 0xFF80],
             IR => self.interrupt_register,
         }
     }
 
+    fn read_unusable(&self, address: u16) -> u8 {
+        match self.model {
+            // CGB revision E repeats the high nibble of the address' low byte
+            Model::Cgb => {
+                let nibble = (address & 0xF0) as u8;
+                nibble | nibble >> 4
+            }
+            // The DMG returns 0xFF while the PPU has OAM locked in modes 2 and 3
+            _ if self.io[(gb::lcd_stat - IO_START) as usize] & 0x2 != 0 => 0xFF,
+            _ => 0x00,
+        }
+    }
+
     pub fn read_2_bytes(&self, a: u16) -> u16 {
         (self.read_byte(a) as u16) | (self.read_byte(a + 1) as u16) << 8
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
-        if address == 0xFF41 {
+        if address == 0xFF41 || (self.dma.active() && address < IO_START) {
             return;
         };
         match address {
-            ROM0_START..=ROM0_END => self.rom_bank0[address as usize] = value,
-            ROM1_START..=ROM1_END => self.rom_bank1[(address as usize) - 0x4000] = value,
+            ROM0_START..=ROM1_END => self.cartridge.write_rom(address, value),
             VRAM_START..=VRAM_END => self.vram[(address as usize) - 0x8000] = value,
-            ERAM_START..=ERAM_END => self.eram[(address as usize) - 0xA000] = value,
+            ERAM_START..=ERAM_END => self.cartridge.write_ram(address, value),
             WRAM_START..=WRAM_END => self.wram[(address as usize) - 0xC000] = value,
-            0xE000..=0xFDFF => {}
+            ECHO_START..=ECHO_END => self.wram[(address - ECHO_START) as usize] = value,
             OAM_START..=OAM_END => self.oam[(address as usize) - 0xFE00] = value,
-            0xFEA0..=0xFEFF => {}
+            UNUSABLE_START..=UNUSABLE_END => {}
+            gb::joypad => self.joypad.write(value),
+            serial::SB..=serial::SC => self.serial.write(address, value),
+            timer::DIV..=timer::TAC => self.timer.write(address, value),
+            apu::NR10..=apu::WAVE_RAM_END => self.apu.write(address, value),
             IO_START..=IO_END => match address {
-                gb::joypad => self.io[gb::joypad as usize - 0xFF00] |= value & 0x30,
                 gb::lcd_stat => self.io[gb::lcd_stat as usize - 0xFF00] |= value & 0xF8,
+                gb::key1_addr if self.model == Model::Cgb => {
+                    let key1 = &mut self.io[(gb::key1_addr - IO_START) as usize];
+                    *key1 = (*key1 & 0x80) | 0x7E | (value & 0x01);
+                }
+                gb::key1_addr => {}
+                gb::dma_reg => {
+                    self.io[gb::dma_reg as usize - 0xFF00] = value;
+                    self.dma.start(value);
+                }
                 _ => self.io[(address as usize) - 0xFF00] = value,
             },
             HRAM_START..=HRAM_END => self.hram[(address as usize) - 0xFF80] = value,
@@ -129,12 +192,102 @@ impl Memory {
         self.write_byte(a, (value >> 8) as u8);
     }
 
+    // Any button in a selected row being held pulls its P1 input line low
+    pub fn joypad_line_low(&self) -> bool {
+        self.read_byte(gb::joypad) & 0x0F != 0x0F
+    }
+
+    pub fn reset_div(&mut self) {
+        self.timer.reset_divider();
+    }
+
+    pub fn double_speed(&self) -> bool {
+        self.model == Model::Cgb && self.io[(gb::key1_addr - IO_START) as usize] & 0x80 != 0
+    }
+
+    // STOP with KEY1 bit 0 set toggles the CGB between normal and double speed
+    pub fn try_speed_switch(&mut self) -> bool {
+        let key1 = &mut self.io[(gb::key1_addr - IO_START) as usize];
+        if self.model != Model::Cgb || *key1 & 0x01 == 0 {
+            return false;
+        }
+        *key1 = (*key1 ^ 0x80) & !0x01;
+        self.timer.double_speed = *key1 & 0x80 != 0;
+        true
+    }
+
     pub fn replace_bootrom(&mut self) {
-        self.rom_bank0
-            .splice(0..0x100, self.rom_low_bytes.as_slice().iter().cloned());
+        self.bootrom_mapped = false;
     }
 
     pub fn update_lcd_stat(&mut self, value: u8) {
         self.io[gb::lcd_stat as usize - 0xFF00] = value
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::cartridge::test::build_rom;
+    use std::env;
+    use std::fs;
+
+    // Blank 32 KiB cartridge with a zeroed boot ROM mapped over it
+    pub fn blank_memory() -> Memory {
+        let cartridge = Cartridge::from_bytes(build_rom(0x00, 0x00, 0x00)).unwrap();
+        Memory::initialize(cartridge, vec![0; (BOOTROM_END + 1) as usize], Model::Dmg)
+    }
+
+    #[test]
+    fn oam_dma_copies_160_bytes_and_blocks_the_cpu() {
+        let mut memory = blank_memory();
+        for i in 0..0xA0 {
+            memory.write_byte(0xC100 + i, i as u8);
+        }
+        memory.write_byte(HRAM_START, 0x42);
+        memory.write_byte(gb::dma_reg, 0xC1);
+        memory.tick(1);
+        assert!(memory.dma.active());
+        memory.tick(10);
+        assert_eq!(memory.oam[9], 9);
+        assert_eq!(memory.read_byte(0xC100), 0xFF);
+        assert_eq!(memory.read_byte(HRAM_START), 0x42);
+        memory.write_byte(0xC100, 0x99);
+        memory.tick(150);
+        assert!(!memory.dma.active());
+        assert_eq!(memory.read_byte(0xC100), 0x00);
+        assert_eq!(
+            &memory.oam[..],
+            &(0..0xA0).map(|i| i as u8).collect::<Vec<u8>>()[..]
+        );
+    }
+
+    #[test]
+    fn echo_ram_mirrors_wram() {
+        let mut memory = blank_memory();
+        memory.write_byte(0xC123, 0x12);
+        assert_eq!(memory.read_byte(0xE123), 0x12);
+        memory.write_byte(0xFDFF, 0x34);
+        assert_eq!(memory.read_byte(0xDDFF), 0x34);
+    }
+
+    #[test]
+    fn unusable_region_depends_on_model() {
+        let mut memory = blank_memory();
+        memory.write_byte(0xFEA0, 0x12);
+        assert_eq!(memory.read_byte(0xFEA0), 0x00);
+        memory.update_lcd_stat(0x83);
+        assert_eq!(memory.read_byte(0xFEA0), 0xFF);
+        memory.model = Model::Cgb;
+        assert_eq!(memory.read_byte(0xFEA5), 0xAA);
+        assert_eq!(memory.read_byte(0xFEF0), 0xFF);
+        assert_eq!(memory.read_byte(0xFEC1), 0xCC);
+    }
+
+    // Tests that check the real DMG boot ROM read it from $BOOTROM
+    pub fn dmg_bootrom_memory() -> Memory {
+        let cartridge = Cartridge::from_bytes(build_rom(0x00, 0x00, 0x00)).unwrap();
+        let bootrom = fs::read(env::var("BOOTROM").unwrap()).unwrap();
+        Memory::initialize(cartridge, bootrom, Model::Dmg)
+    }
+}
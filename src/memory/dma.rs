@@ -0,0 +1,53 @@
+// OAM DMA copies 160 bytes from `value << 8` into OAM, one byte per M-cycle
+pub const OAM_DMA_LENGTH: u16 = 0xA0;
+
+#[derive(Clone)]
+pub struct OamDma {
+    source: u16,
+    // Bytes copied so far, None when no transfer is running
+    progress: Option<u16>,
+    // The first byte is copied one M-cycle after the 0xFF46 write
+    starting: bool,
+}
+
+impl OamDma {
+    pub fn new() -> OamDma {
+        OamDma {
+            source: 0,
+            progress: None,
+            starting: false,
+        }
+    }
+
+    // Writing 0xFF46 while a transfer runs restarts it from the new source
+    pub fn start(&mut self, value: u8) {
+        self.source = (value as u16) << 8;
+        self.progress = Some(0);
+        self.starting = true;
+    }
+
+    // True while bytes are being copied and the CPU is locked out of the bus
+    pub fn active(&self) -> bool {
+        self.progress.is_some() && !self.starting
+    }
+
+    // Advance one M-cycle, returning the source address and OAM offset to copy
+    pub fn step(&mut self) -> Option<(u16, usize)> {
+        if self.starting {
+            self.starting = false;
+            return None;
+        }
+        let progress = self.progress?;
+        // Sources past WRAM read the echo of it, as on the DMG
+        let mut source = self.source + progress;
+        if source >= 0xE000 {
+            source -= 0x2000;
+        }
+        self.progress = if progress + 1 < OAM_DMA_LENGTH {
+            Some(progress + 1)
+        } else {
+            None
+        };
+        Some((source, progress as usize))
+    }
+}
@@ -0,0 +1,249 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::cartridge::header::{CgbSupport, Licensee, TITLE_START};
+use crate::cartridge::Cartridge;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Model {
+    Dmg0,
+    Dmg,
+    Mgb,
+    Sgb,
+    Cgb,
+}
+
+// Register values the boot ROM leaves behind when it jumps to 0x0100
+pub struct PostBootRegisters {
+    pub af: u16,
+    pub bc: u16,
+    pub de: u16,
+    pub hl: u16,
+}
+
+// The registered trademark tile the DMG boot ROM draws after the logo
+const TRADEMARK_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+const LOGO_TILE_DATA: usize = 0x0010;
+const LOGO_TILE_MAP_ROW_1: usize = 0x1904;
+const LOGO_TILE_MAP_ROW_2: usize = 0x1924;
+const TRADEMARK_TILE_MAP: usize = 0x1910;
+
+impl Model {
+    pub fn post_boot_registers(&self, cartridge: &Cartridge) -> PostBootRegisters {
+        // Half carry and carry are only left clear when the header checksum is 0
+        let dmg_flags = if cartridge.header.header_checksum == 0 {
+            0x80
+        } else {
+            0xB0
+        };
+        match self {
+            Model::Dmg0 => PostBootRegisters {
+                af: 0x0100,
+                bc: 0xFF13,
+                de: 0x00C1,
+                hl: 0x8403,
+            },
+            Model::Dmg => PostBootRegisters {
+                af: 0x0100 | dmg_flags,
+                bc: 0x0013,
+                de: 0x00D8,
+                hl: 0x014D,
+            },
+            Model::Mgb => PostBootRegisters {
+                af: 0xFF00 | dmg_flags,
+                bc: 0x0013,
+                de: 0x00D8,
+                hl: 0x014D,
+            },
+            Model::Sgb => PostBootRegisters {
+                af: 0x0100,
+                bc: 0x0014,
+                de: 0x0000,
+                hl: 0xC060,
+            },
+            Model::Cgb if cartridge.header.cgb != CgbSupport::None => PostBootRegisters {
+                af: 0x1180,
+                bc: 0x0000,
+                de: 0xFF56,
+                hl: 0x000D,
+            },
+            Model::Cgb => {
+                // In DMG compatibility mode B holds the title checksum used to
+                // pick a palette, which is only computed for Nintendo titles
+                let nintendo = match &cartridge.header.licensee {
+                    Licensee::Old(code) => *code == 0x01,
+                    Licensee::New(code) => code == "01",
+                };
+                let b = if nintendo {
+                    cartridge.rom[TITLE_START..TITLE_START + 16]
+                        .iter()
+                        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+                } else {
+                    0
+                };
+                PostBootRegisters {
+                    af: 0x1180,
+                    bc: (b as u16) << 8,
+                    de: 0x0008,
+                    hl: if b == 0x43 || b == 0x58 {
+                        0x991A
+                    } else {
+                        0x007C
+                    },
+                }
+            }
+        }
+    }
+
+    // IO register values at 0x0100, as (address, value)
+    pub fn post_boot_io(&self) -> Vec<(u16, u8)> {
+        let cgb = *self == Model::Cgb;
+        vec![
+            (0xFF00, 0xCF),
+            (0xFF01, 0x00),
+            (0xFF02, if cgb { 0x7F } else { 0x7E }),
+            // DIV depends on how long the boot ROM ran, which is only fixed
+            // for the DMG boot ROMs
+            (
+                0xFF04,
+                match self {
+                    Model::Dmg0 => 0x18,
+                    Model::Dmg | Model::Mgb => 0xAB,
+                    Model::Sgb | Model::Cgb => 0x00,
+                },
+            ),
+            (0xFF05, 0x00),
+            (0xFF06, 0x00),
+            (0xFF07, 0xF8),
+            (0xFF0F, 0xE1),
+            (0xFF10, 0x80),
+            (0xFF11, 0xBF),
+            (0xFF12, 0xF3),
+            (0xFF13, 0xFF),
+            (0xFF14, 0xBF),
+            (0xFF16, 0x3F),
+            (0xFF17, 0x00),
+            (0xFF18, 0xFF),
+            (0xFF19, 0xBF),
+            (0xFF1A, 0x7F),
+            (0xFF1B, 0xFF),
+            (0xFF1C, 0x9F),
+            (0xFF1D, 0xFF),
+            (0xFF1E, 0xBF),
+            (0xFF20, 0xFF),
+            (0xFF21, 0x00),
+            (0xFF22, 0x00),
+            (0xFF23, 0xBF),
+            (0xFF24, 0x77),
+            (0xFF25, 0xF3),
+            (0xFF26, if *self == Model::Sgb { 0xF0 } else { 0xF1 }),
+            (0xFF40, 0x91),
+            // Hardware reads 0x85, but the PPU starts at the top of line 0,
+            // so begin in OAM search to reach VBlank and its interrupt on time
+            (0xFF41, 0x86),
+            (0xFF42, 0x00),
+            (0xFF43, 0x00),
+            (0xFF44, 0x00),
+            (0xFF45, 0x00),
+            (0xFF46, if cgb { 0x00 } else { 0xFF }),
+            (0xFF47, 0xFC),
+            // OBP0 and OBP1 aren't initialised, 0xFF is what most units show
+            (0xFF48, 0xFF),
+            (0xFF49, 0xFF),
+            (0xFF4A, 0x00),
+            (0xFF4B, 0x00),
+        ]
+    }
+
+    // Only the DMG family boot ROMs leave the scrolled in logo in VRAM
+    pub fn draws_logo(&self) -> bool {
+        matches!(self, Model::Dmg0 | Model::Dmg | Model::Mgb)
+    }
+}
+
+// Recreate the VRAM contents the DMG boot ROM leaves behind. Each nibble of the
+// header logo is stretched to a byte with every bit doubled, then written to
+// two consecutive tile rows in the low bitplane
+pub fn draw_logo(vram: &mut [u8], logo: &[u8]) {
+    let mut address = LOGO_TILE_DATA;
+    for byte in logo {
+        for nibble in &[byte >> 4, byte & 0x0F] {
+            let mut stretched = 0u8;
+            for bit in (0..4).rev() {
+                let value = (nibble >> bit) & 0x1;
+                stretched = (stretched << 2) | (value << 1) | value;
+            }
+            vram[address] = stretched;
+            vram[address + 2] = stretched;
+            address += 4;
+        }
+    }
+    for (i, byte) in TRADEMARK_TILE.iter().enumerate() {
+        vram[address + i * 2] = *byte;
+    }
+    for i in 0..12 {
+        vram[LOGO_TILE_MAP_ROW_1 + i] = i as u8 + 1;
+        vram[LOGO_TILE_MAP_ROW_2 + i] = i as u8 + 13;
+    }
+    vram[TRADEMARK_TILE_MAP] = 0x19;
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Model, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "dmg0" => Ok(Model::Dmg0),
+            "dmg" => Ok(Model::Dmg),
+            "mgb" => Ok(Model::Mgb),
+            "sgb" => Ok(Model::Sgb),
+            "cgb" => Ok(Model::Cgb),
+            _ => Err(format!(
+                "unknown model \"{}\", expected one of dmg0, dmg, mgb, sgb, cgb",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let model_string = match self {
+            Model::Dmg0 => "DMG0",
+            Model::Dmg => "DMG",
+            Model::Mgb => "MGB",
+            Model::Sgb => "SGB",
+            Model::Cgb => "CGB",
+        };
+        write!(f, "{}", model_string)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::header::NINTENDO_LOGO;
+    use crate::cartridge::test::build_rom;
+
+    #[test]
+    fn dmg_flags_follow_header_checksum() {
+        let mut cartridge = Cartridge::from_bytes(build_rom(0x00, 0x00, 0x00)).unwrap();
+        cartridge.header.header_checksum = 0x00;
+        assert_eq!(Model::Dmg.post_boot_registers(&cartridge).af, 0x0180);
+        cartridge.header.header_checksum = 0x3C;
+        assert_eq!(Model::Dmg.post_boot_registers(&cartridge).af, 0x01B0);
+        assert_eq!(Model::Mgb.post_boot_registers(&cartridge).af, 0xFFB0);
+    }
+
+    #[test]
+    fn logo_is_drawn_like_the_boot_rom() {
+        let mut vram = vec![0; 0x2000];
+        draw_logo(&mut vram, &NINTENDO_LOGO);
+        // 0xCE -> 0xC, 0xE -> 0xF0, 0xFC
+        assert_eq!(&vram[0x10..0x18], &[0xF0, 0, 0xF0, 0, 0xFC, 0, 0xFC, 0]);
+        assert_eq!(vram[0x190], 0x3C);
+        assert_eq!(vram[0x1904], 1);
+        assert_eq!(vram[0x192F], 24);
+        assert_eq!(vram[0x1910], 0x19);
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio::AudioSink;
+use crate::gb;
+
+// A frame is 70224 T-cycles of the 4 MiHz clock, about 59.73 Hz
+const FRAME_SECONDS: f64 = (gb::cycles_per_frame * 4) as f64 / 4_194_304.0;
+// Falling further behind than this drops the lost time instead of rushing
+const MAX_LAG_FRAMES: u32 = 4;
+// How much audio to keep queued ahead of the player
+const TARGET_AUDIO_SECONDS: f64 = 0.05;
+// The most the sample rate is stretched to refill the queue, too little to
+// hear as a change in pitch
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Pacing {
+    // Wait for the audio player to use up what was queued, so the sound
+    // card's clock sets the speed
+    Audio,
+    // Sleep to a fixed schedule of frames at the hardware rate
+    WallClock,
+    Unlimited,
+}
+
+pub struct Pacer {
+    pacing: Pacing,
+    frame_duration: Duration,
+    deadline: Instant,
+    // Interleaved samples, so twice the sample rate
+    samples_per_second: f64,
+    target_fill: usize,
+    last_frame: Option<Instant>,
+    pub stats: DriftStats,
+}
+
+// How closely frames matched the hardware rate, only counting frames that
+// ran at normal speed
+pub struct DriftStats {
+    pacing: Pacing,
+    speed: f64,
+    frames: u64,
+    seconds: f64,
+    late_frames: u64,
+    max_lateness: Duration,
+    resyncs: u64,
+    audio_frames: u64,
+    fill_sum: f64,
+    min_fill: f64,
+    max_ratio: f64,
+}
+
+impl Pacer {
+    // A speed of 2.0 runs frames twice as fast as hardware. Audio pacing
+    // only works at normal speed, so other speeds go by the wall clock
+    pub fn new(pacing: Pacing, speed: f64, sample_rate: u32) -> Pacer {
+        let pacing = match pacing {
+            _ if speed <= 0.0 => Pacing::Unlimited,
+            Pacing::Audio if (speed - 1.0).abs() > f64::EPSILON => Pacing::WallClock,
+            pacing => pacing,
+        };
+        Pacer {
+            pacing,
+            frame_duration: Duration::from_secs_f64(FRAME_SECONDS / speed.max(f64::EPSILON)),
+            deadline: Instant::now(),
+            samples_per_second: sample_rate as f64 * 2.0,
+            target_fill: (sample_rate as f64 * TARGET_AUDIO_SECONDS) as usize * 2,
+            last_frame: None,
+            stats: DriftStats {
+                pacing,
+                speed,
+                frames: 0,
+                seconds: 0.0,
+                late_frames: 0,
+                max_lateness: Duration::from_secs(0),
+                resyncs: 0,
+                audio_frames: 0,
+                fill_sum: 0.0,
+                min_fill: f64::MAX,
+                max_ratio: 1.0,
+            },
+        }
+    }
+
+    // Waits until the next frame is due and returns the audio rate adjustment
+    // to use for it. Paused or fast-forwarded frames still wait for the wall
+    // clock but aren't counted in the statistics
+    pub fn end_frame(&mut self, sink: &dyn AudioSink, realtime: bool) -> f32 {
+        let ratio = match self.pacing {
+            Pacing::Unlimited => 1.0,
+            Pacing::Audio if realtime && sink.buffered().is_some() => self.sync_to_audio(sink),
+            _ => {
+                self.sync_to_clock(realtime);
+                1.0
+            }
+        };
+        let now = Instant::now();
+        match self.last_frame {
+            Some(last) if realtime => {
+                self.stats.frames += 1;
+                self.stats.seconds += (now - last).as_secs_f64();
+            }
+            _ => {}
+        }
+        self.last_frame = if realtime { Some(now) } else { None };
+        ratio as f32
+    }
+
+    fn sync_to_clock(&mut self, realtime: bool) {
+        self.deadline += self.frame_duration;
+        let now = Instant::now();
+        if now < self.deadline {
+            thread::sleep(self.deadline - now);
+        }
+        let lateness = Instant::now().saturating_duration_since(self.deadline);
+        if realtime && lateness > Duration::from_millis(1) {
+            self.stats.late_frames += 1;
+            self.stats.max_lateness = self.stats.max_lateness.max(lateness);
+        }
+        if lateness > self.frame_duration * MAX_LAG_FRAMES {
+            self.deadline = Instant::now();
+            self.stats.resyncs += 1;
+        }
+    }
+
+    fn sync_to_audio(&mut self, sink: &dyn AudioSink) -> f64 {
+        // A player that stopped reading would otherwise hang emulation
+        let give_up = Instant::now() + self.frame_duration * 2;
+        let mut fill = sink.buffered().unwrap_or(0);
+        while fill > self.target_fill && Instant::now() < give_up {
+            thread::sleep(POLL_INTERVAL);
+            fill = sink.buffered().unwrap_or(0);
+        }
+        // Keeps the clock schedule current for when pacing falls back to it
+        self.deadline = Instant::now();
+
+        // Running low means emulation is behind, so make a little more sound
+        // per frame until the queue refills
+        let shortfall = (self.target_fill as f64 - fill as f64) / self.target_fill as f64;
+        let ratio = 1.0 + MAX_RATE_ADJUSTMENT * shortfall.max(-1.0).min(1.0);
+        let fill_seconds = fill as f64 / self.samples_per_second;
+        self.stats.audio_frames += 1;
+        self.stats.fill_sum += fill_seconds;
+        self.stats.min_fill = self.stats.min_fill.min(fill_seconds);
+        self.stats.max_ratio = self.stats.max_ratio.max(ratio);
+        ratio
+    }
+}
+
+impl fmt::Display for DriftStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.frames == 0 {
+            return write!(f, "Pacing: no frames at normal speed");
+        }
+        let expected = self.frames as f64 * FRAME_SECONDS / self.speed;
+        write!(
+            f,
+            "Pacing ({:?}): {} frames in {:.2} s, {:.3} fps (target {:.3}), drift {:+.1} ms",
+            self.pacing,
+            self.frames,
+            self.seconds,
+            self.frames as f64 / self.seconds,
+            self.speed / FRAME_SECONDS,
+            (self.seconds - expected) * 1000.0
+        )?;
+        if self.late_frames > 0 || self.resyncs > 0 {
+            write!(
+                f,
+                ", {} late frames (worst {:.1} ms), {} resyncs",
+                self.late_frames,
+                self.max_lateness.as_secs_f64() * 1000.0,
+                self.resyncs
+            )?;
+        }
+        if self.audio_frames > 0 {
+            write!(
+                f,
+                ", audio queue {:.1} ms average, {:.1} ms lowest, rate up to x{:.4}",
+                self.fill_sum / self.audio_frames as f64 * 1000.0,
+                self.min_fill * 1000.0,
+                self.max_ratio
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullSink;
+
+    #[test]
+    fn hardware_frame_rate() {
+        assert!((1.0 / FRAME_SECONDS - 59.7275).abs() < 0.001);
+    }
+
+    #[test]
+    fn wall_clock_keeps_to_the_frame_rate() {
+        let mut pacer = Pacer::new(Pacing::WallClock, 4.0, 48000);
+        let start = Instant::now();
+        for _ in 0..8 {
+            pacer.end_frame(&NullSink, true);
+        }
+        let expected = Duration::from_secs_f64(8.0 * FRAME_SECONDS / 4.0);
+        // The schedule starts when the pacer is made, just before start
+        assert!(start.elapsed() + Duration::from_millis(1) >= expected);
+        assert_eq!(pacer.stats.frames, 7);
+    }
+
+    #[test]
+    fn audio_pacing_needs_a_real_time_sink_and_normal_speed() {
+        assert_eq!(
+            Pacer::new(Pacing::Audio, 2.0, 48000).pacing,
+            Pacing::WallClock
+        );
+        assert_eq!(
+            Pacer::new(Pacing::Audio, 0.0, 48000).pacing,
+            Pacing::Unlimited
+        );
+        let mut pacer = Pacer::new(Pacing::Audio, 1.0, 48000);
+        // NullSink has no queue to watch, so this falls back to the clock
+        let start = Instant::now();
+        assert_eq!(pacer.end_frame(&NullSink, true), 1.0);
+        assert!(
+            start.elapsed() + Duration::from_millis(1) >= Duration::from_secs_f64(FRAME_SECONDS)
+        );
+    }
+}
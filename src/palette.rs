@@ -0,0 +1,88 @@
+// DMG shades as 0x00RRGGBB, from lightest (colour 0) to darkest (colour 3)
+pub type Palette = [u32; 4];
+
+pub const GREEN: Palette = [0x009bbc0f, 0x008bac0f, 0x00306230, 0x000f380f];
+// Game Boy Pocket
+pub const GRAYSCALE: Palette = [0x00ffffff, 0x00aaaaaa, 0x00555555, 0x00000000];
+// Game Boy Light with the backlight on
+pub const LIGHT: Palette = [0x0000b581, 0x00009a71, 0x0000694a, 0x00004f3b];
+pub const HIGH_CONTRAST: Palette = [0x00ffffff, 0x00ffcc00, 0x000055ff, 0x00000000];
+// Yellow, orange and blue stay apart with red-green colour blindness, and
+// each shade is also darker than the last
+pub const COLORBLIND: Palette = [0x00f0e442, 0x00e69f00, 0x000072b2, 0x00000000];
+
+// In the order the next palette hotkey cycles through them
+pub const PRESETS: [(&str, Palette); 5] = [
+    ("green", GREEN),
+    ("pocket", GRAYSCALE),
+    ("light", LIGHT),
+    ("high_contrast", HIGH_CONTRAST),
+    ("colorblind", COLORBLIND),
+];
+
+// A preset name or four hex colours, e.g. "#e0f8d0, #88c070, #346856, #081820"
+pub fn from_name(name: &str) -> Result<Palette, String> {
+    let preset = match name.to_ascii_lowercase().as_str() {
+        "grayscale" | "greyscale" => "pocket",
+        "colourblind" => "colorblind",
+        _ => name,
+    };
+    if let Some((_, palette)) = PRESETS
+        .iter()
+        .find(|(preset_name, _)| preset_name.eq_ignore_ascii_case(preset))
+    {
+        return Ok(*palette);
+    }
+    if name.contains(',') {
+        return parse_hex(name);
+    }
+    let names: Vec<&str> = PRESETS.iter().map(|(name, _)| *name).collect();
+    Err(format!(
+        "unknown palette \"{}\", expected one of {} or four hex colours",
+        name,
+        names.join(", ")
+    ))
+}
+
+// Four comma separated RRGGBB colours, lightest first, each optionally
+// starting with # or 0x
+pub fn parse_hex(text: &str) -> Result<Palette, String> {
+    let error = || format!("expected four colours like #e0f8d0, got \"{}\"", text);
+    let colors: Vec<&str> = text.split(',').map(str::trim).collect();
+    if colors.len() != 4 {
+        return Err(error());
+    }
+    let mut palette = [0; 4];
+    for (shade, color) in palette.iter_mut().zip(colors) {
+        let digits = color.trim_start_matches('#').trim_start_matches("0x");
+        if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(error());
+        }
+        *shade = u32::from_str_radix(digits, 16).unwrap();
+    }
+    Ok(palette)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_presets_by_name() {
+        assert_eq!(from_name("Green"), Ok(GREEN));
+        assert_eq!(from_name("grayscale"), Ok(GRAYSCALE));
+        assert_eq!(from_name("high_contrast"), Ok(HIGH_CONTRAST));
+        assert!(from_name("sepia").is_err());
+    }
+
+    #[test]
+    fn parses_hex_palettes() {
+        assert_eq!(
+            from_name("#E0F8D0, #88c070,0x346856, 081820"),
+            Ok([0x00e0f8d0, 0x0088c070, 0x00346856, 0x00081820])
+        );
+        assert!(parse_hex("#e0f8d0, #88c070, #346856").is_err());
+        assert!(parse_hex("#e0f8d0, #88c070, #346856, #08182").is_err());
+        assert!(parse_hex("#e0f8d0, #88c070, #346856, #08182g").is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+const COLOR_TYPE_GRAYSCALE: u8 = 0;
+const COLOR_TYPE_RGB: u8 = 2;
+// Deflate's stored blocks hold at most this many bytes
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+// 8 bit grayscale image, one byte per pixel
+pub fn write_grayscale(path: &Path, width: usize, height: usize, pixels: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, encode(width, height, COLOR_TYPE_GRAYSCALE, 1, pixels))
+}
+
+// 8 bit RGB image from 0x00RRGGBB pixels, the framebuffer's format
+pub fn write_rgb(path: &Path, width: usize, height: usize, pixels: &[u32]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let bytes: Vec<u8> = pixels
+        .iter()
+        .flat_map(|pixel| pixel.to_be_bytes()[1..].to_vec())
+        .collect();
+    fs::write(path, encode(width, height, COLOR_TYPE_RGB, 3, &bytes))
+}
+
+fn encode(
+    width: usize,
+    height: usize,
+    color_type: u8,
+    bytes_per_pixel: usize,
+    pixels: &[u8],
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, default compression, filter and no interlacing
+    header.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+    // Every scanline starts with its filter type, 0 for none
+    let stride = width * bytes_per_pixel;
+    let mut raw = Vec::with_capacity((stride + 1) * height);
+    for row in pixels.chunks(stride).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+// Printouts and screenshots are small, so skip compression and store the data
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(if blocks.peek().is_none() { 0x01 } else { 0x00 });
+        let length = block.len() as u16;
+        zlib.extend_from_slice(&length.to_le_bytes());
+        zlib.extend_from_slice(&(!length).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(data).to_be_bytes());
+    zlib
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksums_match_reference_values() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn encodes_header_and_stored_data() {
+        let png = encode(2, 2, COLOR_TYPE_GRAYSCALE, 1, &[0x00, 0xFF, 0x55, 0xAA]);
+        assert_eq!(&png[..8], &SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..24], &[0, 0, 0, 2, 0, 0, 0, 2]);
+        // IDAT holds the zlib header, one final stored block and the filter bytes
+        assert_eq!(&png[37..41], b"IDAT");
+        assert_eq!(&png[41..48], &[0x78, 0x01, 0x01, 0x06, 0x00, 0xF9, 0xFF]);
+        assert_eq!(&png[48..54], &[0x00, 0x00, 0xFF, 0x00, 0x55, 0xAA]);
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+}
@@ -4,20 +4,28 @@ use std::fmt;
 use crate::cpu::interrupt_handler::*;
 use crate::gb;
 use crate::memory::Memory;
+use crate::palette::{self, Palette};
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Pixel {
     color_index: u8,
-    // 0 == background pixel, 1 == sprite pixel
+    // 1 for sprite pixels that go behind background colours 1-3, otherwise 0
     prio: u8,
+    // BGP, OBP0 or OBP1, read when the pixel is pushed to the screen
+    palette: u16,
 }
 
 impl fmt::Display for Pixel {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "[color_index: {} prio: {}]", self.color_index, self.prio)
+        write!(
+            f,
+            "[color_index: {} prio: {} palette: {:#06x}]",
+            self.color_index, self.prio, self.palette
+        )
     }
 }
 
+#[derive(Clone)]
 pub struct Object {
     y: u8,
     x: u8,
@@ -36,6 +44,7 @@ pub enum LcdcFlag {
     BackgroundEnable,
 }
 
+#[derive(Clone)]
 enum PpuMode {
     OamSearch = 2,
     PixelTransfer = 3,
@@ -43,6 +52,7 @@ enum PpuMode {
     Vblank = 1,
 }
 
+#[derive(Clone)]
 pub struct Ppu {
     bg_fifo: VecDeque<Pixel>,
     obj_fifo: VecDeque<Pixel>,
@@ -51,6 +61,7 @@ pub struct Ppu {
     fetcher_x_position: u16,
     sprite_buffer: Vec<Object>,
     oam_offset: usize,
+    pub palette: Palette,
 }
 
 impl Ppu {
@@ -63,6 +74,7 @@ impl Ppu {
             fetcher_x_position: 0,
             sprite_buffer: Vec::with_capacity(10),
             oam_offset: 0,
+            palette: palette::GREEN,
         }
     }
 
@@ -102,22 +114,33 @@ impl Ppu {
                 // OAM search
                 0x2 => {
                     //println!("In oam search mode @ cycle {}", curr_cycle);
-                    if self.sprite_buffer.len() == 10 {
-                        continue;
-                    }
-                    let oam = &memory.oam;
-                    for i in self.oam_offset..self.oam_offset + 4 {
-                        let index = i * 4;
-                        if oam[index] <= ly + 16 && oam[index] + 8 > ly + 16 && oam[index + 1] != 0
-                        {
-                            self.sprite_buffer.push(Object {
-                                y: oam[index],
-                                x: oam[index + 1],
-                                index: oam[index + 2],
-                                attr: oam[index + 3],
-                            })
+                    // Two objects a cycle covers all 40 in the 20 cycles of the
+                    // mode. OAM DMA owns OAM, so the PPU sees 0xFF and finds no
+                    // objects
+                    if self.oam_offset < 40 && !memory.dma.active() {
+                        let height = if Ppu::check_lcdc(memory, LcdcFlag::ObjectSize) {
+                            16
+                        } else {
+                            8
+                        };
+                        let line = ly as u16 + 16;
+                        let oam = &memory.oam;
+                        for i in self.oam_offset..self.oam_offset + 2 {
+                            let index = i * 4;
+                            let y = oam[index] as u16;
+                            // Only the first ten objects on a line are drawn. X
+                            // isn't checked, so ones off screen still count
+                            if self.sprite_buffer.len() < 10 && y <= line && y + height > line {
+                                self.sprite_buffer.push(Object {
+                                    y: oam[index],
+                                    x: oam[index + 1],
+                                    index: oam[index + 2],
+                                    attr: oam[index + 3],
+                                })
+                            }
                         }
                     }
+                    self.oam_offset += 2;
                 }
                 0x3 => {
                     if self.bg_fifo.len() <= 8 {
@@ -137,21 +160,22 @@ impl Ppu {
                             + self.fetcher_x_position as u16
                             + (scx / 8) as u16
                             + background_tile_offset;
-                        let tile_number = memory.read_byte(tile_index);
+                        let tile_number = memory.read_vram(tile_index);
                         let tile_data_address = base_tile_data_location
                             + (tile_number as u16 * 0x10)
                             + (2 * ((ly + scy) % 8) as u16);
-                        let tile_data_low = memory.read_byte(tile_data_address);
-                        let tile_data_high = memory.read_byte(tile_data_address + 0x1);
+                        let tile_data_low = memory.read_vram(tile_data_address);
+                        let tile_data_high = memory.read_vram(tile_data_address + 0x1);
 
                         let mut pixels = Vec::with_capacity(8);
                         for i in 0..=7 {
                             let color_index =
-                                ((tile_data_high >> i) & 1) | (((tile_data_low >> i) & 1) << 1);
+                                ((tile_data_low >> i) & 1) | (((tile_data_high >> i) & 1) << 1);
                             assert!(color_index < 4);
                             pixels.push(Pixel {
                                 color_index,
                                 prio: 0,
+                                palette: gb::bgp_addr,
                             });
                         }
                         pixels.reverse();
@@ -163,9 +187,22 @@ impl Ppu {
                     if !self.bg_fifo.is_empty() {
                         // push pixel
                         let ly = memory.read_byte(gb::ly_addr);
-                        // TODO: use BGP or OBP to map color index -> color value
                         for _ in 0..2 {
-                            let curr_pixel = self.bg_fifo.pop_front().unwrap();
+                            if Ppu::check_lcdc(memory, LcdcFlag::ObjectEnable) {
+                                self.fetch_objects(memory, ly);
+                            }
+                            let bg_pixel = self.bg_fifo.pop_front().unwrap();
+                            // Colour 0 is transparent, and BG priority objects
+                            // only show through background colour 0
+                            let curr_pixel = match self.obj_fifo.pop_front() {
+                                Some(obj_pixel)
+                                    if obj_pixel.color_index != 0
+                                        && (obj_pixel.prio == 0 || bg_pixel.color_index == 0) =>
+                                {
+                                    obj_pixel
+                                }
+                                _ => bg_pixel,
+                            };
                             if ly as usize * gb::screen_width + self.x as usize >= buffer.len() {
                                 panic!("ly: {} * 144 + x: {}", ly, self.x);
                             }
@@ -176,8 +213,11 @@ impl Ppu {
                             //     ly,
                             //     curr_cycle
                             // );
+                            // Games fade by rewriting the palette mid-frame, so it's
+                            // read per pixel rather than once per line
+                            let palette = memory.read_byte(curr_pixel.palette);
                             buffer[ly as usize * gb::screen_width + self.x as usize] =
-                                Ppu::get_color(curr_pixel.color_index) as u32;
+                                self.get_color(curr_pixel.color_index, palette);
                             self.x += 1;
                         }
                     }
@@ -219,7 +259,10 @@ impl Ppu {
             0x2 => {
                 if curr_cycle == 20 {
                     //println!("Switching mode from pixel transfer to pixel transfer");
-                    // sort sprite buffer by x
+                    // On DMG the object with the lower X wins where they
+                    // overlap, then the one earlier in OAM. The sort is stable,
+                    // so fetching in this order gives both
+                    self.sprite_buffer.sort_by_key(|object| object.x);
                     memory.update_lcd_stat((lcd_stat & 0xFC) | 0x3)
                 }
             }
@@ -234,6 +277,9 @@ impl Ppu {
             0x0 => {
                 if curr_cycle == 114 {
                     self.bg_fifo.clear();
+                    self.obj_fifo.clear();
+                    self.sprite_buffer.clear();
+                    self.oam_offset = 0;
                     if ly == 144 {
                         interrupt_handler.set_interrupt(memory, Interrupt::VBlank);
                         //println!("Switching mode from hblank to vblank");
@@ -254,13 +300,172 @@ impl Ppu {
             _ => panic!("Invalid mode"),
         }
     }
-    pub fn get_color(i: u8) -> u32 {
-        match i {
-            3 => 0x000f380f,
-            2 => 0x00306230,
-            1 => 0x008bac0f,
-            0 => 0x009bbc0f,
-            _ => panic!(),
+    // Fetches the objects that start at the next pixel, or that hang off the
+    // left edge at the start of the line, and mixes them into the object FIFO.
+    // Pixels already in the FIFO came from higher priority objects, so a new
+    // object only fills in where they're transparent
+    fn fetch_objects(&mut self, memory: &Memory, ly: u8) {
+        let tall = Ppu::check_lcdc(memory, LcdcFlag::ObjectSize);
+        let height: u16 = if tall { 16 } else { 8 };
+        while !self.sprite_buffer.is_empty() && self.sprite_buffer[0].x <= self.x + 8 {
+            let object = self.sprite_buffer.remove(0);
+            let mut row = (ly as u16 + 16 - object.y as u16) % height;
+            if object.attr & 0x40 != 0 {
+                row = height - 1 - row;
+            }
+            // The bottom bit of the tile number is ignored for 8x16 objects
+            let tile = if tall {
+                object.index & 0xFE
+            } else {
+                object.index
+            };
+            let tile_data_address = 0x8000 + tile as u16 * 0x10 + row * 2;
+            let tile_data_low = memory.read_vram(tile_data_address);
+            let tile_data_high = memory.read_vram(tile_data_address + 0x1);
+            let palette = if object.attr & 0x10 != 0 {
+                gb::obp1_addr
+            } else {
+                gb::obp0_addr
+            };
+
+            // Columns left of the screen are dropped
+            let clipped = self.x + 8 - object.x;
+            for column in clipped..8 {
+                let bit = if object.attr & 0x20 != 0 {
+                    column
+                } else {
+                    7 - column
+                };
+                let position = (column - clipped) as usize;
+                if position == self.obj_fifo.len() {
+                    self.obj_fifo.push_back(Pixel {
+                        color_index: 0,
+                        prio: 0,
+                        palette,
+                    });
+                }
+                if self.obj_fifo[position].color_index == 0 {
+                    self.obj_fifo[position] = Pixel {
+                        color_index: ((tile_data_low >> bit) & 1)
+                            | (((tile_data_high >> bit) & 1) << 1),
+                        prio: object.attr >> 7,
+                        palette,
+                    };
+                }
+            }
+        }
+    }
+
+    // Each 2 bits of a palette register pick the shade for one colour index,
+    // colour 0 in the lowest bits
+    pub fn get_color(&self, i: u8, palette: u8) -> u32 {
+        let shade = (palette >> (i * 2)) & 0x3;
+        self.palette[shade as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cartridge::test::build_rom;
+    use crate::cartridge::Cartridge;
+    use crate::cpu::Cpu;
+    use crate::model::Model;
+
+    #[test]
+    fn palette_register_maps_colour_to_shade() {
+        let ppu = Ppu::new(&InterruptHandler::new());
+        // The usual 0xE4 maps each colour to the shade of the same number
+        for i in 0..4 {
+            assert_eq!(ppu.get_color(i, 0xE4), palette::GREEN[i as usize]);
         }
+        // 0x1B inverts, 0x00 fades everything to the lightest shade
+        assert_eq!(ppu.get_color(0, 0x1B), palette::GREEN[3]);
+        assert_eq!(ppu.get_color(3, 0x1B), palette::GREEN[0]);
+        assert_eq!(ppu.get_color(2, 0x00), palette::GREEN[0]);
+    }
+
+    #[test]
+    fn post_boot_state_reaches_vblank() {
+        let cartridge = Cartridge::from_bytes(build_rom(0x00, 0x00, 0x00)).unwrap();
+        let mut cpu = Cpu::new(Memory::initialize(cartridge, Vec::new(), Model::Dmg));
+        let mut ppu = Ppu::new(&cpu.interrupt_handler);
+        let mut buffer = vec![0; gb::total_pixels];
+        cpu.memory.write_byte(gb::iflags, 0);
+        ppu.step(
+            gb::cycles_per_frame,
+            &mut cpu.memory,
+            &cpu.interrupt_handler,
+            &mut buffer,
+        );
+        assert_eq!(cpu.memory.read_byte(gb::iflags) & 0x1, 0x1);
+    }
+
+    #[test]
+    fn oam_dma_during_oam_search_still_advances() {
+        let mut memory = crate::memory::test::blank_memory();
+        let interrupt_handler = InterruptHandler::new();
+        let mut ppu = Ppu::new(&interrupt_handler);
+        let mut buffer = vec![0; gb::total_pixels];
+        memory.write_byte(gb::lcdc_addr, 0x83);
+        memory.update_lcd_stat(0x82);
+        memory.write_byte(gb::dma_reg, 0xC0);
+        memory.tick(1);
+        assert!(memory.dma.active());
+        ppu.step(114, &mut memory, &interrupt_handler, &mut buffer);
+        assert_eq!(memory.read_byte(gb::ly_addr), 1);
+    }
+
+    #[test]
+    fn draws_objects() {
+        let mut memory = crate::memory::test::blank_memory();
+        let interrupt_handler = InterruptHandler::new();
+        let mut ppu = Ppu::new(&interrupt_handler);
+        let mut buffer = vec![0; gb::total_pixels];
+        // LCD, objects and background on, with an empty background
+        memory.write_byte(gb::lcdc_addr, 0x83);
+        memory.write_byte(gb::bgp_addr, 0xE4);
+        memory.write_byte(gb::obp0_addr, 0xE4);
+        memory.write_byte(gb::obp1_addr, 0x1B);
+        // Tile 1 is colour 1 on the left half and transparent on the right,
+        // tile 2 is solid colour 3
+        for row in 0..8 {
+            memory.write_byte(0x8010 + row * 2, 0xF0);
+            memory.write_byte(0x8020 + row * 2, 0xFF);
+            memory.write_byte(0x8021 + row * 2, 0xFF);
+        }
+        let objects: &[[u8; 4]] = &[
+            // Line 0: plain, then flipped with OBP1 just right of it
+            [16, 8, 1, 0x00],
+            [16, 12, 1, 0x30],
+            // Line 16: the lower X wins even though it's later in OAM
+            [32, 18, 2, 0x00],
+            [32, 16, 1, 0x00],
+        ];
+        for (i, object) in objects.iter().enumerate() {
+            memory.oam[i * 4..i * 4 + 4].copy_from_slice(object);
+        }
+        // Line 40: eleven objects in a row, and only ten are drawn
+        for i in 0..11 {
+            let index = (objects.len() + i) * 4;
+            memory.oam[index..index + 4].copy_from_slice(&[56, 8 + 8 * i as u8, 2, 0x00]);
+        }
+
+        ppu.step(
+            gb::cycles_per_frame * 2,
+            &mut memory,
+            &interrupt_handler,
+            &mut buffer,
+        );
+        let pixel = |x: usize, y: usize| buffer[y * gb::screen_width + x];
+        assert_eq!(pixel(0, 0), palette::GREEN[1]);
+        assert_eq!(pixel(4, 0), palette::GREEN[0]);
+        assert_eq!(pixel(8, 0), palette::GREEN[2]);
+        assert_eq!(pixel(8, 7), palette::GREEN[2]);
+        assert_eq!(pixel(8, 8), palette::GREEN[0]);
+        assert_eq!(pixel(10, 16), palette::GREEN[1]);
+        assert_eq!(pixel(12, 16), palette::GREEN[3]);
+        assert_eq!(pixel(72, 40), palette::GREEN[3]);
+        assert_eq!(pixel(80, 40), palette::GREEN[0]);
     }
 }
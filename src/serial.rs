@@ -0,0 +1,332 @@
+pub mod link;
+pub mod printer;
+
+use std::time::{Duration, Instant};
+
+use crate::serial::link::{LinkMessage, LinkTransport};
+
+pub const SB: u16 = 0xFF01;
+pub const SC: u16 = 0xFF02;
+
+// With the internal clock a bit is shifted every 128 M-cycles (8192 Hz), or
+// every 4 M-cycles (262144 Hz) with the CGB's fast clock
+const CYCLES_PER_BIT: u32 = 128;
+const FAST_CYCLES_PER_BIT: u32 = 4;
+// How long the clocking side waits for the peer's byte before treating the
+// cable as unplugged and shifting in 0xFF. Emulation carries on meanwhile
+const LINK_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub struct Serial {
+    sb: u8,
+    sc: u8,
+    cgb: bool,
+    bits_left: u8,
+    cycles: u32,
+    // SB when the transfer started, which is what the peer receives
+    outgoing: u8,
+    interrupt: bool,
+    // Only kept when someone reads it, otherwise it would grow forever
+    capture: bool,
+    sent: Vec<u8>,
+    link: Option<Box<dyn LinkTransport>>,
+    // Numbers the transfers this side clocks, to match up their replies
+    transfer_id: u8,
+    // A reply that arrived before the transfer finished clocking
+    reply: Option<u8>,
+    // When to give up on the reply once all 8 bits have been clocked
+    reply_deadline: Option<Instant>,
+    // The latest byte the peer clocked before we were ready for it, as
+    // (id, byte). Older ones were already given up on by the peer
+    pending_transfer: Option<(u8, u8)>,
+}
+
+// A copy has no cable plugged in, the transport can only have one owner
+impl Clone for Serial {
+    fn clone(&self) -> Serial {
+        Serial {
+            sent: self.sent.clone(),
+            link: None,
+            ..*self
+        }
+    }
+}
+
+impl Serial {
+    pub fn new(cgb: bool) -> Serial {
+        Serial {
+            sb: 0,
+            sc: 0,
+            cgb,
+            bits_left: 0,
+            cycles: 0,
+            outgoing: 0,
+            interrupt: false,
+            capture: false,
+            sent: Vec::new(),
+            link: None,
+            transfer_id: 0,
+            reply: None,
+            reply_deadline: None,
+            pending_transfer: None,
+        }
+    }
+
+    pub fn connect(&mut self, link: Box<dyn LinkTransport>) {
+        self.link = Some(link);
+    }
+
+    // Unplugs the cable without closing it so it can be moved to another Serial
+    pub fn take_link(&mut self) -> Option<Box<dyn LinkTransport>> {
+        self.link.take()
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            SB => self.sb,
+            // Unused bits read as 1, bit 1 only exists on the CGB
+            SC if self.cgb => self.sc | 0x7C,
+            SC => self.sc | 0x7E,
+            _ => panic!("Address {:#0x} is not a serial register", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            SB => self.sb = value,
+            SC => {
+                self.sc = value & if self.cgb { 0x83 } else { 0x81 };
+                if self.sc & 0x80 != 0 {
+                    self.bits_left = 8;
+                    self.cycles = 0;
+                    self.outgoing = self.sb;
+                    if self.sc & 0x01 != 0 {
+                        self.transfer_id = self.transfer_id.wrapping_add(1);
+                        self.reply = None;
+                        self.reply_deadline = None;
+                        self.send(LinkMessage::Transfer {
+                            id: self.transfer_id,
+                            byte: self.outgoing,
+                        });
+                    }
+                } else {
+                    self.bits_left = 0;
+                }
+            }
+            _ => panic!("Address {:#0x} is not a serial register", address),
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        self.poll_link();
+        // Transfers on the external clock wait for a peer to drive it
+        if self.bits_left == 0 || self.sc & 0x01 == 0 {
+            return;
+        }
+        let cycles_per_bit = if self.sc & 0x02 != 0 {
+            FAST_CYCLES_PER_BIT
+        } else {
+            CYCLES_PER_BIT
+        };
+        self.cycles += cycles;
+        if self.link.is_some() {
+            // The peer's byte only arrives whole, so swap it in at the end.
+            // A slow peer makes the transfer finish late rather than
+            // stalling emulation
+            if self.cycles >= cycles_per_bit * self.bits_left as u32 {
+                let deadline = *self
+                    .reply_deadline
+                    .get_or_insert_with(|| Instant::now() + LINK_TIMEOUT);
+                if let Some(incoming) = self.reply.take() {
+                    self.finish(incoming);
+                } else if Instant::now() >= deadline {
+                    self.finish(0xFF);
+                }
+            }
+            return;
+        }
+        while self.cycles >= cycles_per_bit && self.bits_left > 0 {
+            self.cycles -= cycles_per_bit;
+            // Without a peer the input line is pulled high
+            self.shift(1);
+        }
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        let interrupt = self.interrupt;
+        self.interrupt = false;
+        interrupt
+    }
+
+    // Starts or stops recording sent bytes for take_sent
+    pub fn capture_output(&mut self, capture: bool) {
+        self.capture = capture;
+        self.sent.clear();
+    }
+
+    // Bytes sent since the last call, e.g. the text test ROMs print
+    pub fn take_sent(&mut self) -> Vec<u8> {
+        self.sent.drain(..).collect()
+    }
+
+    fn shift(&mut self, bit_in: u8) {
+        self.sb = self.sb << 1 | bit_in;
+        self.bits_left -= 1;
+        if self.bits_left == 0 {
+            self.finish(self.sb);
+        }
+    }
+
+    fn finish(&mut self, incoming: u8) {
+        self.sb = incoming;
+        self.bits_left = 0;
+        self.reply_deadline = None;
+        self.sc &= 0x7F;
+        self.interrupt = true;
+        if self.capture {
+            self.sent.push(self.outgoing);
+        }
+    }
+
+    fn poll_link(&mut self) {
+        loop {
+            let message = match self.link.as_mut().map(|link| link.poll()) {
+                Some(Ok(Some(message))) => message,
+                Some(Err(e)) => {
+                    self.disconnect(e);
+                    break;
+                }
+                _ => break,
+            };
+            match message {
+                // Hold on to the peer's byte until the game is waiting for one,
+                // so both sides stay in step even when one emulator runs ahead
+                LinkMessage::Transfer { id, byte } => self.pending_transfer = Some((id, byte)),
+                // Replies to transfers that already timed out are dropped
+                LinkMessage::Reply { id, byte } if id == self.transfer_id => {
+                    self.reply = Some(byte)
+                }
+                LinkMessage::Reply { .. } => {}
+            }
+        }
+        if self.bits_left > 0 {
+            if let Some((id, byte)) = self.pending_transfer.take() {
+                self.send(LinkMessage::Reply { id, byte: self.sb });
+                // If both sides drive the clock neither receives the other
+                if self.sc & 0x01 == 0 {
+                    self.finish(byte);
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, message: LinkMessage) {
+        if let Some(Err(e)) = self.link.as_mut().map(|link| link.send(message)) {
+            self.disconnect(e);
+        }
+    }
+
+    fn disconnect(&mut self, e: std::io::Error) {
+        eprintln!("Link cable disconnected: {}", e);
+        self.link = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serial::link::LoopbackTransport;
+
+    #[test]
+    fn internal_clock_transfer_takes_1024_cycles() {
+        let mut serial = Serial::new(false);
+        serial.capture_output(true);
+        serial.write(SB, b'A');
+        serial.write(SC, 0x81);
+        serial.tick(1023);
+        assert_eq!(serial.read(SC), 0xFF);
+        assert!(!serial.take_interrupt());
+        serial.tick(1);
+        assert_eq!(serial.read(SC), 0x7F);
+        assert_eq!(serial.read(SB), 0xFF);
+        assert!(serial.take_interrupt());
+        assert_eq!(serial.take_sent(), vec![b'A']);
+    }
+
+    #[test]
+    fn external_clock_waits_for_peer() {
+        let mut serial = Serial::new(false);
+        serial.capture_output(true);
+        serial.write(SB, 0x12);
+        serial.write(SC, 0x80);
+        serial.tick(10000);
+        assert_eq!(serial.read(SC), 0xFE);
+        assert!(serial.take_sent().is_empty());
+    }
+
+    #[test]
+    fn loopback_exchanges_bytes() {
+        let (a, b) = LoopbackTransport::pair();
+        let mut master = Serial::new(false);
+        let mut slave = Serial::new(false);
+        master.connect(Box::new(a));
+        slave.connect(Box::new(b));
+        slave.write(SB, 0x42);
+        slave.write(SC, 0x80);
+        master.write(SB, 0x99);
+        master.write(SC, 0x81);
+        slave.tick(1);
+        assert_eq!(slave.read(SB), 0x99);
+        assert!(slave.take_interrupt());
+        master.tick(1023);
+        assert!(!master.take_interrupt());
+        master.tick(1);
+        assert_eq!(master.read(SB), 0x42);
+        assert_eq!(master.read(SC), 0x7F);
+        assert!(master.take_interrupt());
+    }
+
+    #[test]
+    fn stale_link_messages_are_skipped() {
+        let (a, mut peer) = LoopbackTransport::pair();
+        let mut serial = Serial::new(false);
+        serial.connect(Box::new(a));
+
+        // Waiting for the peer doesn't hold up emulation
+        serial.write(SC, 0x81);
+        serial.tick(1024);
+        assert!(!serial.take_interrupt());
+        assert_eq!(
+            peer.poll().unwrap(),
+            Some(LinkMessage::Transfer { id: 1, byte: 0 })
+        );
+        // A late reply to an earlier transfer is ignored
+        peer.send(LinkMessage::Reply { id: 0, byte: 0x55 }).unwrap();
+        peer.send(LinkMessage::Reply { id: 1, byte: 0x42 }).unwrap();
+        serial.tick(1);
+        assert!(serial.take_interrupt());
+        assert_eq!(serial.read(SB), 0x42);
+
+        // Of two transfers the peer clocked, it only still waits on the last
+        peer.send(LinkMessage::Transfer { id: 7, byte: 0x11 })
+            .unwrap();
+        peer.send(LinkMessage::Transfer { id: 8, byte: 0x22 })
+            .unwrap();
+        serial.tick(1);
+        serial.write(SB, 0x99);
+        serial.write(SC, 0x80);
+        serial.tick(1);
+        assert_eq!(serial.read(SB), 0x22);
+        assert_eq!(
+            peer.poll().unwrap(),
+            Some(LinkMessage::Reply { id: 8, byte: 0x99 })
+        );
+    }
+
+    #[test]
+    fn cgb_fast_clock() {
+        let mut serial = Serial::new(true);
+        serial.write(SC, 0x83);
+        serial.tick(32);
+        assert!(serial.take_interrupt());
+    }
+}
@@ -0,0 +1,173 @@
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+// The id numbers each transfer so a reply that arrives after the clocking
+// side gave up on it can't be mistaken for the answer to the next one
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum LinkMessage {
+    // The clocking side shifted out a byte
+    Transfer { id: u8, byte: u8 },
+    // The byte the other side shifted out at the same time
+    Reply { id: u8, byte: u8 },
+}
+
+// Something plugged into the serial port: another emulator or a peripheral
+pub trait LinkTransport {
+    fn send(&mut self, message: LinkMessage) -> io::Result<()>;
+    // Returns straight away if nothing has arrived
+    fn poll(&mut self) -> io::Result<Option<LinkMessage>>;
+}
+
+impl LinkMessage {
+    fn encode(&self) -> [u8; 3] {
+        match *self {
+            LinkMessage::Transfer { id, byte } => [0x01, id, byte],
+            LinkMessage::Reply { id, byte } => [0x02, id, byte],
+        }
+    }
+
+    fn decode(bytes: [u8; 3]) -> io::Result<LinkMessage> {
+        let (id, byte) = (bytes[1], bytes[2]);
+        match bytes[0] {
+            0x01 => Ok(LinkMessage::Transfer { id, byte }),
+            0x02 => Ok(LinkMessage::Reply { id, byte }),
+            kind => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown link message {:#04x}", kind),
+            )),
+        }
+    }
+}
+
+// Messages between two ends in the same process, for tests
+pub struct LoopbackTransport {
+    sender: Sender<LinkMessage>,
+    receiver: Receiver<LinkMessage>,
+}
+
+impl LoopbackTransport {
+    pub fn pair() -> (LoopbackTransport, LoopbackTransport) {
+        let (a_sender, b_receiver) = mpsc::channel();
+        let (b_sender, a_receiver) = mpsc::channel();
+        (
+            LoopbackTransport {
+                sender: a_sender,
+                receiver: a_receiver,
+            },
+            LoopbackTransport {
+                sender: b_sender,
+                receiver: b_receiver,
+            },
+        )
+    }
+}
+
+impl LinkTransport for LoopbackTransport {
+    fn send(&mut self, message: LinkMessage) -> io::Result<()> {
+        self.sender.send(message).map_err(|_| disconnected())
+    }
+
+    fn poll(&mut self) -> io::Result<Option<LinkMessage>> {
+        match self.receiver.try_recv() {
+            Ok(message) => Ok(Some(message)),
+            Err(TryRecvError::Empty) => Ok(None),
+            Err(TryRecvError::Disconnected) => Err(disconnected()),
+        }
+    }
+}
+
+// A socket to another gbemu. A thread reads incoming messages into a channel
+// so polling never blocks emulation
+pub struct StreamTransport {
+    writer: Box<dyn Write + Send>,
+    incoming: LoopbackTransport,
+}
+
+impl StreamTransport {
+    fn new<S: Read + Write + Send + 'static>(stream: S, reader: S) -> StreamTransport {
+        let (mut feeder, incoming) = LoopbackTransport::pair();
+        thread::spawn(move || {
+            let mut reader = reader;
+            let mut bytes = [0; 3];
+            while reader.read_exact(&mut bytes).is_ok() {
+                match LinkMessage::decode(bytes) {
+                    Ok(message) if feeder.send(message).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        StreamTransport {
+            writer: Box::new(stream),
+            incoming,
+        }
+    }
+
+    // Addresses with a '/' are Unix socket paths, anything else is host:port
+    pub fn listen(address: &str) -> io::Result<StreamTransport> {
+        #[cfg(unix)]
+        {
+            if address.contains('/') {
+                // A socket left behind by an earlier run would make bind
+                // fail, but anything else at the path isn't ours to delete
+                match fs::symlink_metadata(address) {
+                    Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(address)?,
+                    Ok(_) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} exists and is not a socket", address),
+                        ))
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+                let (stream, _) = UnixListener::bind(address)?.accept()?;
+                let reader = stream.try_clone()?;
+                return Ok(StreamTransport::new(stream, reader));
+            }
+        }
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        StreamTransport::from_tcp(stream)
+    }
+
+    pub fn connect(address: &str) -> io::Result<StreamTransport> {
+        #[cfg(unix)]
+        {
+            if address.contains('/') {
+                let stream = UnixStream::connect(address)?;
+                let reader = stream.try_clone()?;
+                return Ok(StreamTransport::new(stream, reader));
+            }
+        }
+        StreamTransport::from_tcp(TcpStream::connect(address)?)
+    }
+
+    fn from_tcp(stream: TcpStream) -> io::Result<StreamTransport> {
+        // Every message is three bytes, so don't let Nagle hold them back
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        Ok(StreamTransport::new(stream, reader))
+    }
+}
+
+impl LinkTransport for StreamTransport {
+    fn send(&mut self, message: LinkMessage) -> io::Result<()> {
+        self.writer.write_all(&message.encode())?;
+        self.writer.flush()
+    }
+
+    fn poll(&mut self) -> io::Result<Option<LinkMessage>> {
+        self.incoming.poll()
+    }
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "link cable disconnected")
+}
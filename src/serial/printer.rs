@@ -0,0 +1,346 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::PathBuf;
+
+use crate::png;
+use crate::serial::link::{LinkMessage, LinkTransport};
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+// The printer buffers up to 9 bands of 2 tile rows, 160x16 pixels each
+const BAND_BYTES: usize = 640;
+const MAX_BANDS: usize = 9;
+const WIDTH: usize = 160;
+const BAND_HEIGHT: usize = 16;
+// Printed shades from white to black
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+// The device ID the printer answers with during the keep-alive byte
+const ALIVE: u8 = 0x81;
+
+const STATUS_CHECKSUM_ERROR: u8 = 0x01;
+const STATUS_PRINTING: u8 = 0x02;
+const STATUS_IMAGE_DATA_FULL: u8 = 0x04;
+const STATUS_UNPROCESSED_DATA: u8 = 0x08;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Command {
+    Initialize = 0x01,
+    Print = 0x02,
+    Data = 0x04,
+    Status = 0x0F,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum PacketState {
+    Magic(usize),
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    KeepAlive,
+    Status,
+}
+
+// The Game Boy Printer, which the game drives as the serial clock master.
+// Each print command writes the buffered image out as a PNG
+pub struct Printer {
+    output_dir: PathBuf,
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    packet: Vec<u8>,
+    checksum: u16,
+    received_checksum: u16,
+    image: Vec<u8>,
+    status: u8,
+    replies: VecDeque<LinkMessage>,
+    pub printed: Vec<PathBuf>,
+}
+
+impl Command {
+    fn from_byte(byte: u8) -> Option<Command> {
+        match byte {
+            0x01 => Some(Command::Initialize),
+            0x02 => Some(Command::Print),
+            0x04 => Some(Command::Data),
+            0x0F => Some(Command::Status),
+            _ => None,
+        }
+    }
+}
+
+impl Printer {
+    pub fn new(output_dir: PathBuf) -> Printer {
+        Printer {
+            output_dir,
+            state: PacketState::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            packet: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            image: Vec::new(),
+            status: 0,
+            replies: VecDeque::new(),
+            printed: Vec::new(),
+        }
+    }
+
+    // Feed one byte from the game and return the byte shifted back
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            PacketState::Magic(i) if byte == MAGIC[i] && i + 1 == MAGIC.len() => {
+                PacketState::Command
+            }
+            PacketState::Magic(i) if byte == MAGIC[i] => PacketState::Magic(i + 1),
+            PacketState::Magic(_) => PacketState::Magic(if byte == MAGIC[0] { 1 } else { 0 }),
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.packet.clear();
+                if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.packet.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.packet.len() == self.length as usize {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                PacketState::KeepAlive
+            }
+            PacketState::KeepAlive => {
+                reply = ALIVE;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                reply = self.run_command();
+                PacketState::Magic(0)
+            }
+        };
+        reply
+    }
+
+    // Returns the status byte the printer sends at the end of the packet
+    fn run_command(&mut self) -> u8 {
+        if self.checksum != self.received_checksum {
+            return self.status | STATUS_CHECKSUM_ERROR;
+        }
+        match Command::from_byte(self.command) {
+            Some(Command::Initialize) => {
+                self.image.clear();
+                self.status = 0;
+            }
+            Some(Command::Data) => {
+                let data = if self.compressed {
+                    decompress(&self.packet)
+                } else {
+                    self.packet.clone()
+                };
+                let room = BAND_BYTES * MAX_BANDS - self.image.len();
+                self.image.extend(data.into_iter().take(room));
+                if !self.image.is_empty() {
+                    self.status |= STATUS_UNPROCESSED_DATA;
+                }
+                if self.image.len() == BAND_BYTES * MAX_BANDS {
+                    self.status |= STATUS_IMAGE_DATA_FULL;
+                }
+            }
+            Some(Command::Print) if self.packet.len() >= 4 => {
+                let margins = self.packet[1];
+                let palette = self.packet[2];
+                self.print(margins >> 4, margins & 0x0F, palette);
+                // The game sees one busy status before the print finishes
+                self.status = STATUS_PRINTING | STATUS_IMAGE_DATA_FULL;
+                return self.status;
+            }
+            Some(Command::Status) => {
+                let status = self.status;
+                self.status &= !STATUS_PRINTING;
+                if status & STATUS_PRINTING == 0 {
+                    self.status &= !STATUS_IMAGE_DATA_FULL;
+                }
+                return status;
+            }
+            Some(Command::Print) | None => {}
+        }
+        self.status
+    }
+
+    fn print(&mut self, margin_before: u8, margin_after: u8, palette: u8) {
+        let bands = self.image.len() / BAND_BYTES;
+        if bands == 0 {
+            return;
+        }
+        // Each unit of margin feeds one band of blank paper
+        let margin_before = margin_before as usize * BAND_HEIGHT;
+        let height = margin_before + bands * BAND_HEIGHT + margin_after as usize * BAND_HEIGHT;
+        let mut pixels = vec![SHADES[0]; WIDTH * height];
+        for (tile_index, tile) in self.image[..bands * BAND_BYTES].chunks(16).enumerate() {
+            let band = tile_index / 40;
+            let tile_x = tile_index % 20;
+            let tile_y = band * 2 + (tile_index % 40) / 20;
+            for row in 0..8 {
+                let (low, high) = (tile[row * 2], tile[row * 2 + 1]);
+                for bit in 0..8 {
+                    let color = ((high >> (7 - bit)) & 1) << 1 | ((low >> (7 - bit)) & 1);
+                    let shade = (palette >> (color * 2)) & 0x3;
+                    let y = margin_before + tile_y * 8 + row;
+                    pixels[y * WIDTH + tile_x * 8 + bit] = SHADES[shade as usize];
+                }
+            }
+        }
+        let path = self.next_path();
+        match png::write_grayscale(&path, WIDTH, height, &pixels) {
+            Ok(()) => {
+                eprintln!("Printed to {}", path.display());
+                self.printed.push(path);
+            }
+            Err(e) => eprintln!("Could not write printout {}: {}", path.display(), e),
+        }
+        self.image.clear();
+    }
+
+    fn next_path(&self) -> PathBuf {
+        let mut number = 1;
+        loop {
+            let path = self.output_dir.join(format!("print_{:04}.png", number));
+            if !path.exists() {
+                return path;
+            }
+            number += 1;
+        }
+    }
+}
+
+// Runs are a control byte then either n + 1 literal bytes, or with bit 7 set
+// one byte repeated (n & 0x7F) + 2 times
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(byte) = data.get(i) {
+                output.extend(std::iter::repeat(*byte).take((control & 0x7F) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            output.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    output
+}
+
+impl LinkTransport for Printer {
+    fn send(&mut self, message: LinkMessage) -> io::Result<()> {
+        if let LinkMessage::Transfer { id, byte } = message {
+            let byte = self.exchange(byte);
+            self.replies.push_back(LinkMessage::Reply { id, byte });
+        }
+        Ok(())
+    }
+
+    fn poll(&mut self) -> io::Result<Option<LinkMessage>> {
+        Ok(self.replies.pop_front())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn send_packet(printer: &mut Printer, command: u8, compression: u8, data: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x88, 0x33, command, compression];
+        packet.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        packet.extend_from_slice(data);
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0x00, 0x00]);
+        packet.iter().map(|byte| printer.exchange(*byte)).collect()
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(
+            decompress(&[0x81, 0xAB, 0x01, 0x01, 0x02]),
+            vec![0xAB, 0xAB, 0xAB, 0x01, 0x02]
+        );
+    }
+
+    #[test]
+    fn answers_alive_and_status() {
+        let mut printer = Printer::new(env::temp_dir());
+        let reply = send_packet(&mut printer, 0x0F, 0, &[]);
+        assert_eq!(&reply[reply.len() - 2..], &[ALIVE, 0x00]);
+        let mut reply = send_packet(&mut printer, 0x04, 0, &[0; 16]);
+        assert_eq!(reply.pop(), Some(STATUS_UNPROCESSED_DATA));
+        // A corrupt checksum is flagged
+        let mut packet = vec![0x88, 0x33, 0x0F, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+        let status: Vec<u8> = packet.drain(..).map(|b| printer.exchange(b)).collect();
+        assert_eq!(status[9] & STATUS_CHECKSUM_ERROR, STATUS_CHECKSUM_ERROR);
+    }
+
+    #[test]
+    fn prints_a_band_with_margins_and_palette() {
+        let dir = env::temp_dir().join("gbemu_printer_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut printer = Printer::new(dir.clone());
+        send_packet(&mut printer, 0x01, 0, &[]);
+        // One band where every pixel is colour 3, as 640 bytes of 0xFF in runs
+        let runs = [0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFA, 0xFF];
+        send_packet(&mut printer, 0x04, 1, &runs);
+        send_packet(&mut printer, 0x04, 0, &[]);
+        let mut reply = send_packet(&mut printer, 0x02, 0, &[0x01, 0x11, 0xE4, 0x40]);
+        assert_eq!(reply.pop(), Some(STATUS_PRINTING | STATUS_IMAGE_DATA_FULL));
+        assert_eq!(printer.printed.len(), 1);
+        let png = fs::read(&printer.printed[0]).unwrap();
+        // 160 wide, a 16 pixel band plus 16 pixels of margin on each side
+        assert_eq!(&png[16..24], &[0, 0, 0, 160, 0, 0, 0, 48]);
+        // Stored rows are a filter byte then 160 pixels
+        let row = |y: usize| 48 + y * 161 + 1;
+        assert_eq!(png[row(15)], 0xFF);
+        assert_eq!(png[row(16)], 0x00);
+        assert_eq!(png[row(32)], 0xFF);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,13 +1,153 @@
-use std::{fmt, thread, time};
+use std::fmt;
 
-const MICRO_PER_FRAME: u64 = 16667;
+pub const DIV: u16 = 0xFF04;
+pub const TIMA: u16 = 0xFF05;
+pub const TMA: u16 = 0xFF06;
+pub const TAC: u16 = 0xFF07;
 
-pub fn sleep_to_frame_end(start: time::Instant) {
-    let elapsed_micros = (time::Instant::now() - start).as_micros() as u64;
-    if elapsed_micros < MICRO_PER_FRAME {
-        thread::sleep(time::Duration::from_micros(
-            MICRO_PER_FRAME - elapsed_micros,
-        ));
+// The divider counts T-cycles, DIV is its upper byte
+#[derive(Clone)]
+pub struct Timer {
+    divider: u16,
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    // TIMA reads 0 for one M-cycle after overflowing before TMA is loaded
+    overflowed: bool,
+    // The M-cycle TMA is copied in, when TIMA writes are ignored
+    reloading: bool,
+    interrupt: bool,
+    // Moves the APU's edge from DIV bit 4 to bit 5
+    pub double_speed: bool,
+    // Falling edges of DIV bit 4 (bit 5 in double speed) not yet passed on
+    // to the APU's frame sequencer
+    apu_steps: u32,
+}
+
+impl Timer {
+    pub fn new() -> Timer {
+        Timer {
+            divider: 0,
+            tima: 0,
+            tma: 0,
+            tac: 0xF8,
+            overflowed: false,
+            reloading: false,
+            interrupt: false,
+            double_speed: false,
+            apu_steps: 0,
+        }
+    }
+
+    pub fn set_divider(&mut self, divider: u16) {
+        self.divider = divider;
+    }
+
+    pub fn tick(&mut self, cycles: u32) {
+        for _ in 0..cycles {
+            self.reloading = false;
+            if self.overflowed {
+                self.overflowed = false;
+                self.tima = self.tma;
+                self.interrupt = true;
+                self.reloading = true;
+            }
+            let signal = self.signal();
+            let apu_signal = self.apu_signal();
+            self.divider = self.divider.wrapping_add(4);
+            self.check_falling_edge(signal);
+            self.check_apu_edge(apu_signal);
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            DIV => (self.divider >> 8) as u8,
+            TIMA => self.tima,
+            TMA => self.tma,
+            TAC => self.tac,
+            _ => panic!("Address {:#0x} is not a timer register", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            DIV => self.reset_divider(),
+            TIMA if self.reloading => {}
+            TIMA => {
+                // Writing during the overflow cycle cancels the reload
+                self.tima = value;
+                self.overflowed = false;
+            }
+            TMA => {
+                self.tma = value;
+                if self.reloading {
+                    self.tima = value;
+                }
+            }
+            TAC => {
+                let signal = self.signal();
+                self.tac = value | 0xF8;
+                self.check_falling_edge(signal);
+            }
+            _ => panic!("Address {:#0x} is not a timer register", address),
+        }
+    }
+
+    // Resetting can itself cause a falling edge and tick TIMA
+    pub fn reset_divider(&mut self) {
+        let signal = self.signal();
+        let apu_signal = self.apu_signal();
+        self.divider = 0;
+        self.check_falling_edge(signal);
+        self.check_apu_edge(apu_signal);
+    }
+
+    pub fn take_interrupt(&mut self) -> bool {
+        let interrupt = self.interrupt;
+        self.interrupt = false;
+        interrupt
+    }
+
+    pub fn take_apu_steps(&mut self) -> u32 {
+        let steps = self.apu_steps;
+        self.apu_steps = 0;
+        steps
+    }
+
+    fn apu_signal(&self) -> bool {
+        let bit = if self.double_speed { 13 } else { 12 };
+        self.divider & (1 << bit) != 0
+    }
+
+    fn check_apu_edge(&mut self, old_signal: bool) {
+        if old_signal && !self.apu_signal() {
+            self.apu_steps += 1;
+        }
+    }
+
+    // TIMA counts falling edges of the selected divider bit ANDed with the
+    // enable bit, which is why DIV and TAC writes can tick it
+    fn signal(&self) -> bool {
+        let bit = match self.tac & 0x3 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            _ => 7,
+        };
+        self.tac & 0x4 != 0 && self.divider & (1 << bit) != 0
+    }
+
+    fn check_falling_edge(&mut self, old_signal: bool) {
+        if !old_signal || self.signal() {
+            return;
+        }
+        if self.tima == 0xFF {
+            self.tima = 0;
+            self.overflowed = true;
+        } else {
+            self.tima += 1;
+        }
     }
 }
 
@@ -28,3 +168,80 @@ impl fmt::Display for Cycles {
         write!(f, "{}", cycles_string)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn div_is_upper_byte_and_resets_on_write() {
+        let mut timer = Timer::new();
+        timer.tick(64);
+        assert_eq!(timer.read(DIV), 1);
+        timer.write(DIV, 0x55);
+        assert_eq!(timer.read(DIV), 0);
+    }
+
+    #[test]
+    fn div_bit_4_falling_edges_step_the_apu() {
+        let mut timer = Timer::new();
+        // 512 Hz, once every 2048 M-cycles
+        timer.tick(4096);
+        assert_eq!(timer.take_apu_steps(), 2);
+        timer.tick(1024);
+        // Resetting DIV while bit 4 is set is a falling edge too
+        timer.reset_divider();
+        assert_eq!(timer.take_apu_steps(), 1);
+        timer.double_speed = true;
+        timer.tick(2048);
+        assert_eq!(timer.take_apu_steps(), 0);
+    }
+
+    #[test]
+    fn tima_counts_at_selected_rate_and_reloads_late() {
+        let mut timer = Timer::new();
+        // 262144 Hz, once every 4 M-cycles
+        timer.write(TAC, 0x05);
+        timer.write(TMA, 0x80);
+        timer.write(TIMA, 0xFE);
+        timer.tick(8);
+        assert_eq!(timer.read(TIMA), 0x00);
+        assert!(!timer.take_interrupt());
+        timer.tick(1);
+        assert_eq!(timer.read(TIMA), 0x80);
+        assert!(timer.take_interrupt());
+    }
+
+    #[test]
+    fn tima_write_during_overflow_cancels_reload() {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0x05);
+        timer.write(TIMA, 0xFF);
+        timer.tick(4);
+        timer.write(TIMA, 0x10);
+        timer.tick(1);
+        assert_eq!(timer.read(TIMA), 0x10);
+        assert!(!timer.take_interrupt());
+    }
+
+    #[test]
+    fn tma_write_during_reload_reaches_tima() {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0x05);
+        timer.write(TIMA, 0xFF);
+        timer.tick(5);
+        timer.write(TIMA, 0x10);
+        timer.write(TMA, 0x20);
+        assert_eq!(timer.read(TIMA), 0x20);
+    }
+
+    #[test]
+    fn div_reset_ticks_tima_on_falling_edge() {
+        let mut timer = Timer::new();
+        timer.write(TAC, 0x05);
+        timer.tick(2);
+        // Bit 3 is now high, so clearing the divider is a falling edge
+        timer.write(DIV, 0);
+        assert_eq!(timer.read(TIMA), 1);
+    }
+}